@@ -0,0 +1,208 @@
+use chrono::{DateTime, Duration, Utc};
+use log::debug;
+use rusoto_core::Region;
+use rusoto_ec2::{DescribeImagesRequest, Ec2, Ec2Client, Image};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::profile::{get_cache_dir, Profile};
+use crate::{util, AwsInstanceError, Result};
+
+/// How long `DescribeImages` results are kept, unless the profile says otherwise
+pub const DEFAULT_AMI_CACHE_TTL: u64 = 3600;
+
+/// Where and for how long to cache image searches
+#[derive(Clone, Debug)]
+pub struct AmiCache {
+    pub region: String,
+    /// Who's asking, since searches for "self" images differ between accounts
+    pub identity: String,
+    pub ttl_seconds: u64,
+    pub refresh: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct CachedImages {
+    fetched: DateTime<Utc>,
+    images: Vec<CachedImage>,
+}
+
+/// The parts of an `Image` we use, since rusoto's types can't be serialized
+#[derive(Debug, Deserialize, Serialize)]
+struct CachedImage {
+    image_id: Option<String>,
+    state: Option<String>,
+    creation_date: Option<String>,
+    name: Option<String>,
+    description: Option<String>,
+    architecture: Option<String>,
+    owner_id: Option<String>,
+    image_owner_alias: Option<String>,
+    root_device_type: Option<String>,
+    virtualization_type: Option<String>,
+}
+
+impl From<&Image> for CachedImage {
+    fn from(image: &Image) -> Self {
+        CachedImage {
+            image_id: image.image_id.clone(),
+            state: image.state.clone(),
+            creation_date: image.creation_date.clone(),
+            name: image.name.clone(),
+            description: image.description.clone(),
+            architecture: image.architecture.clone(),
+            owner_id: image.owner_id.clone(),
+            image_owner_alias: image.image_owner_alias.clone(),
+            root_device_type: image.root_device_type.clone(),
+            virtualization_type: image.virtualization_type.clone(),
+        }
+    }
+}
+
+impl From<CachedImage> for Image {
+    fn from(image: CachedImage) -> Self {
+        Image {
+            image_id: image.image_id,
+            state: image.state,
+            creation_date: image.creation_date,
+            name: image.name,
+            description: image.description,
+            architecture: image.architecture,
+            owner_id: image.owner_id,
+            image_owner_alias: image.image_owner_alias,
+            root_device_type: image.root_device_type,
+            virtualization_type: image.virtualization_type,
+            ..Default::default()
+        }
+    }
+}
+
+impl AmiCache {
+    pub fn new(profile: &Profile, refresh: bool) -> Self {
+        // Searches against a custom endpoint are kept apart from the real thing
+        let region = match profile.region.clone().unwrap_or_default() {
+            Region::Custom { name, endpoint } => format!("{}@{}", name, endpoint),
+            region => region.name().to_string(),
+        };
+        // Credentials in the environment can belong to any account, whatever the profile
+        let identity = ["AWS_PROFILE", "AWS_ACCESS_KEY_ID"]
+            .iter()
+            .filter_map(|variable| env::var(variable).ok())
+            .filter(|value| !value.is_empty())
+            .fold(profile.name.clone(), |identity, value| {
+                format!("{}+{}", identity, value)
+            });
+        AmiCache {
+            region,
+            identity,
+            ttl_seconds: profile.ami_cache_ttl.unwrap_or(DEFAULT_AMI_CACHE_TTL),
+            refresh,
+        }
+    }
+
+    /// Describe images, using a cached copy of the results of the same search if there's
+    /// one younger than the TTL. If AWS can't be reached, an older copy will do.
+    pub async fn describe_images(
+        &self,
+        ec2_client: &Ec2Client,
+        request: DescribeImagesRequest,
+    ) -> Result<Vec<Image>> {
+        let path = self.cache_path(&request);
+        let cached = read_cache(&path);
+
+        let fresh = cached.as_ref().is_some_and(|cached| {
+            Utc::now() - cached.fetched < Duration::seconds(self.ttl_seconds as i64)
+        });
+        if fresh && !self.refresh {
+            debug!("Using cached images from {}", path.display());
+            return Ok(cached.map(CachedImages::into_images).unwrap_or_default());
+        }
+
+        // rusoto's DescribeImages has no MaxResults or NextToken, and without a page size
+        // EC2 returns every match at once, so there's only ever the one page
+        match ec2_client.describe_images(request).await {
+            Ok(result) => {
+                let images = result.images.unwrap_or_default();
+                write_cache(&path, &images);
+                Ok(images)
+            }
+            Err(error) => match (AwsInstanceError::from(error), cached) {
+                (AwsInstanceError::HttpDispatch { source }, Some(cached)) => {
+                    eprintln!(
+                        "Warning: couldn't reach AWS ({}), using the image list from {}",
+                        source,
+                        cached.fetched.format("%Y-%m-%d %H:%M UTC")
+                    );
+                    Ok(cached.into_images())
+                }
+                (error, _) => Err(error),
+            },
+        }
+    }
+
+    // The same search in the same region always gets the same file
+    fn cache_path(&self, request: &DescribeImagesRequest) -> PathBuf {
+        let mut owners = request.owners.clone().unwrap_or_default();
+        owners.sort();
+        let mut filters: Vec<String> = request
+            .filters
+            .iter()
+            .flatten()
+            .map(|filter| {
+                let mut values = filter.values.clone().unwrap_or_default();
+                values.sort();
+                format!(
+                    "{}={}",
+                    filter.name.as_deref().unwrap_or_default(),
+                    values.join(",")
+                )
+            })
+            .collect();
+        filters.sort();
+        let mut image_ids = request.image_ids.clone().unwrap_or_default();
+        image_ids.sort();
+        let cache_key = format!(
+            "{}|{}|{}|{}|{}",
+            self.region,
+            self.identity,
+            owners.join(","),
+            filters.join(";"),
+            image_ids.join(",")
+        );
+
+        let mut path = get_cache_dir();
+        path.push("amis");
+        path.push(format!(
+            "{}.json",
+            sha1_smol::Sha1::from(cache_key).digest()
+        ));
+        path
+    }
+}
+
+impl CachedImages {
+    fn into_images(self) -> Vec<Image> {
+        self.images.into_iter().map(Image::from).collect()
+    }
+}
+
+fn read_cache(path: &Path) -> Option<CachedImages> {
+    let contents = fs::read_to_string(path).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+fn write_cache(path: &Path, images: &[Image]) {
+    let cached = CachedImages {
+        fetched: Utc::now(),
+        images: images.iter().map(CachedImage::from).collect(),
+    };
+    let result = serde_json::to_string(&cached)
+        .map_err(std::io::Error::from)
+        .and_then(|contents| util::write_private_file(path, contents.as_bytes(), true));
+
+    if let Err(error) = result {
+        debug!("Error caching images in {}: {}", path.display(), error);
+    }
+}
@@ -1,21 +1,39 @@
 use clap::{Command, CommandFactory, Parser, ValueEnum};
 use clap_complete::{generate, Generator, Shell};
 use std::collections::HashMap;
+use std::str::FromStr;
 
-use crate::commands::create::{create_instance, CreateOptions};
-use crate::commands::destroy::destroy_instance;
+use crate::ami_cache::AmiCache;
+use crate::commands::config;
+use crate::commands::create::{
+    create_instance, parse_tag, CreateSettings, LaunchTemplateRef, Volume,
+};
+use crate::commands::destroy::{destroy_instance, DEFAULT_PROTECT_TAG};
+use crate::commands::environment::{down, plan, read_environment, up};
+use crate::commands::image;
+use crate::commands::keypair;
 use crate::commands::list::list;
-use crate::commands::list_amis::list_amis;
+use crate::commands::list_amis::{default_owners, list_amis};
+use crate::commands::list_launch_templates::list_launch_templates;
 use crate::commands::list_security_groups::list_security_groups;
+use crate::commands::protect::protect;
+use crate::commands::security_group::{self, is_icmp, parse_icmp, PortRange, Rule, RuleSource};
+use crate::commands::security_group_file::{
+    apply_security_group, export_security_group, read_security_group_file,
+};
+use crate::commands::snapshot;
 use crate::commands::ssh::ssh;
 use crate::commands::start::start;
 use crate::commands::stop::stop;
+use crate::commands::volume;
+use crate::profile::ConfigFileReader;
+use crate::util::{self, public_ip, DEFAULT_IP_ECHO_URL};
 use crate::Profile;
-use crate::Result;
+use crate::{AwsInstanceError, Result};
+use rusoto_core::Region;
 use rusoto_ec2::Ec2Client;
 
-const DEFAULT_INSTANCE_TYPE: &str = "m1.small";
-
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Debug, Eq, Hash, PartialEq, ValueEnum)]
 pub enum OsNames {
     AmazonLinux,
@@ -45,6 +63,24 @@ impl std::fmt::Display for OsNames {
     }
 }
 
+impl OsNames {
+    /// The account which publishes official AMIs for this OS, and a pattern matching their names
+    pub fn ami_source(&self) -> (&'static str, &'static str) {
+        match self {
+            OsNames::AmazonLinux => ("amazon", "al2023-ami-2023.*"),
+            OsNames::CentOS => ("125523088429", "CentOS Stream 9*"),
+            OsNames::Debian => ("136693071363", "debian-12-*"),
+            OsNames::Fedora => ("125523088429", "Fedora-Cloud-Base-*"),
+            OsNames::RHEL => ("309956199498", "RHEL-9.*"),
+            OsNames::Suse => ("013907871322", "suse-sles-15-*"),
+            OsNames::Ubuntu => (
+                "099720109477",
+                "ubuntu/images/hvm-ssd*/ubuntu-*-22.04-*-server-*",
+            ),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum OsNamesError {
     ParseError(String),
@@ -75,6 +111,12 @@ impl std::str::FromStr for OsNames {
     }
 }
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum ErrorFormat {
+    Text,
+    Json,
+}
+
 #[derive(Debug, Parser)]
 #[command(name = "aws-instance", about = "Manage AWS instances")]
 pub struct CmdLineOptions {
@@ -82,7 +124,7 @@ pub struct CmdLineOptions {
     /// Path to config file
     pub config_file: Option<String>,
 
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     /// Set the AWS profile to use
     pub profile: Option<String>,
 
@@ -90,25 +132,87 @@ pub struct CmdLineOptions {
     /// Set the AWS region to use
     pub region: Option<String>,
 
+    #[arg(long = "endpoint-url")]
+    /// Send EC2 requests to this URL instead of the usual AWS endpoint
+    pub endpoint_url: Option<String>,
+
+    #[arg(long = "max-retries", global = true)]
+    /// Maximum number of times to retry throttled or failed AWS requests [default: 4]
+    pub max_retries: Option<u32>,
+
+    #[arg(
+        long = "error-format",
+        value_enum,
+        default_value = "text",
+        global = true
+    )]
+    /// How to write errors to stderr
+    pub error_format: ErrorFormat,
+
+    #[arg(long = "dry-run", global = true)]
+    /// Check permissions and report what would happen, without making any changes
+    pub dry_run: bool,
+
     #[command(subcommand)]
     pub subcommand: SubCommands,
 }
 
+// Config and image commands set up their own clients, if they need them at all, so they're
+// kept apart from the commands which run with the profile's EC2 client. Only one of these
+// is ever built, so the size of the create options doesn't matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Parser)]
 pub enum SubCommands {
+    #[command(name = "config", about = "Inspect and edit profiles")]
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+
+    #[command(name = "image", about = "Capture and manage AMIs")]
+    Image {
+        #[command(subcommand)]
+        command: ImageCommands,
+    },
+
+    #[command(flatten)]
+    Ec2(Ec2Commands),
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Parser)]
+pub enum Ec2Commands {
     #[command(name = "create", about = "Create a named AWS instance")]
     Create {
         #[arg(name = "NAME")]
         /// Instance name
         name: String,
 
+        #[arg(short = 'T', long)]
+        /// Template from the config file to take settings from
+        template: Option<String>,
+
+        #[arg(short, long = "launch-template", value_parser = LaunchTemplateRef::from_str)]
+        /// EC2 launch template to launch from, as NAME|ID[:VERSION]. Other options override it
+        launch_template: Option<LaunchTemplateRef>,
+
         #[arg(short, long = "ami-id")]
-        /// AMI Image ID to use
-        ami_id: String,
+        /// AMI Image ID to use [default: the latest AMI for --os-name]
+        ami_id: Option<String>,
+
+        #[arg(long)]
+        /// Architecture to look for when finding an AMI by OS name [default: x86_64]
+        architecture: Option<String>,
 
-        #[arg(short, long = "ebs-optimized", default_value = "false")]
-        /// Is it EBS optimized?
-        ebs_optimized: bool,
+        #[arg(
+            short,
+            long = "ebs-optimized",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "true"
+        )]
+        /// Is it EBS optimized? Use --ebs-optimized=false to override a template
+        ebs_optimized: Option<bool>,
 
         #[arg(short, long = "iam-profile")]
         /// IAM profile to use
@@ -123,18 +227,70 @@ pub enum SubCommands {
         keypair_name: Option<String>,
 
         #[arg(short, long = "security-groups")]
-        /// Security groups for the instance
+        /// Security groups for the instance, by ID or name
         security_group_ids: Vec<String>,
 
         #[arg(short, long = "os-name")]
         /// Name of the OS
         os_name: Option<OsNames>,
+
+        #[arg(long)]
+        /// Subnet to launch the instance in
+        subnet: Option<String>,
+
+        #[arg(long = "volume", value_parser = Volume::from_str)]
+        /// EBS volume to attach, as DEVICE:SIZE[:TYPE], e.g. /dev/sdf:100:gp3
+        volumes: Vec<Volume>,
+
+        #[arg(long = "tag", value_parser = parse_tag)]
+        /// Extra tag for the instance, as KEY=VALUE
+        tags: Vec<(String, String)>,
+
+        #[arg(long = "user-data")]
+        /// File containing user data for the instance
+        user_data: Option<String>,
+
+        #[arg(long)]
+        /// Enable termination protection on the new instance
+        protect: bool,
+
+        #[arg(long)]
+        /// Look up the AMI for --os-name again instead of using the cache
+        refresh: bool,
     },
 
     #[command(name = "destroy", about = "Destroy an AWS instance by name")]
     Destroy {
         /// Instance name
         name: String,
+
+        #[arg(long, short)]
+        /// Don't ask for confirmation
+        yes: bool,
+    },
+
+    #[command(
+        name = "down",
+        about = "Stop, or destroy, the instances in an environment file"
+    )]
+    Down {
+        #[arg(short, long = "file")]
+        /// Environment file
+        file: String,
+
+        #[arg(long)]
+        /// Destroy the instances instead of stopping them
+        destroy: bool,
+
+        #[arg(long, short)]
+        /// Don't ask for confirmation before destroying
+        yes: bool,
+    },
+
+    #[command(name = "keypair", about = "Create and manage EC2 key pairs")]
+    Keypair {
+        #[command(subcommand)]
+        command: KeypairCommands,
     },
 
     #[command(name = "list", about = "List AWS instances")]
@@ -161,6 +317,34 @@ pub enum SubCommands {
         #[arg(long)]
         /// Filter images by image name using regular expression
         search: Option<String>,
+
+        #[arg(long = "owner")]
+        /// Image owner: self, amazon, aws-marketplace or an account ID [default: self and the
+        /// OS vendors]
+        owners: Vec<String>,
+
+        #[arg(long)]
+        /// Only show the newest image in each family of names
+        latest: bool,
+
+        #[arg(long)]
+        /// Virtualization type: hvm or paravirtual
+        virtualization: Option<String>,
+
+        #[arg(long = "root-device")]
+        /// Root device type: ebs or instance-store
+        root_device: Option<String>,
+
+        #[arg(long)]
+        /// Search AWS again instead of using cached results
+        refresh: bool,
+    },
+
+    #[command(name = "list-launch-templates", about = "List EC2 launch templates")]
+    ListLaunchTemplates {
+        #[arg(name = "NAME")]
+        /// Launch template name
+        name: Option<String>,
     },
 
     #[command(name = "list-security-groups", about = "List AWS security groups")]
@@ -168,6 +352,81 @@ pub enum SubCommands {
         #[arg(name = "NAME")]
         /// Security group name
         name: Option<String>,
+
+        #[arg(long)]
+        /// Show the instances and network interfaces using each group
+        usage: bool,
+    },
+
+    #[command(
+        name = "plan",
+        about = "Show what up (or down) would do to the instances in an environment file"
+    )]
+    Plan {
+        #[arg(short, long = "file")]
+        /// Environment file
+        file: String,
+
+        #[arg(long)]
+        /// Show what down would do instead of up
+        down: bool,
+
+        #[arg(long, requires = "down")]
+        /// With --down, show what down --destroy would do
+        destroy: bool,
+    },
+
+    #[command(
+        name = "protect",
+        about = "Enable termination protection on an instance"
+    )]
+    Protect {
+        #[arg(name = "NAME")]
+        /// Instance name
+        name: String,
+    },
+
+    #[command(
+        name = "restore",
+        about = "Replace a stopped instance's volumes with ones made from a snapshot set"
+    )]
+    Restore {
+        #[arg(name = "NAME")]
+        /// Instance name
+        name: String,
+
+        #[arg(long = "snapshot-label")]
+        /// Label of the snapshot set to restore
+        label: String,
+
+        #[arg(long, short)]
+        /// Don't ask for confirmation
+        yes: bool,
+    },
+
+    #[command(name = "sg", about = "Create security groups and manage their rules")]
+    Sg {
+        #[command(subcommand)]
+        command: SgCommands,
+    },
+
+    #[command(
+        name = "snapshot",
+        about = "Snapshot every EBS volume attached to an instance",
+        args_conflicts_with_subcommands = true,
+        subcommand_negates_reqs = true
+    )]
+    Snapshot {
+        #[command(subcommand)]
+        command: Option<SnapshotCommands>,
+
+        #[arg(name = "NAME", required = true)]
+        /// Instance name
+        name: Option<String>,
+
+        #[arg(long)]
+        /// Label for the snapshot set, by default the current time
+        label: Option<String>,
     },
 
     #[command(name = "ssh", about = "SSH into an instance")]
@@ -183,6 +442,10 @@ pub enum SubCommands {
         /// Path to SSH key to use
         key: Option<String>,
 
+        #[arg(long)]
+        /// Open port 22 to this machine's public IP address first, if it isn't already
+        open: bool,
+
         /// SSH options
         sshopts: Vec<String>,
     },
@@ -201,6 +464,36 @@ pub enum SubCommands {
         name: String,
     },
 
+    #[command(
+        name = "unprotect",
+        about = "Disable termination protection on an instance"
+    )]
+    Unprotect {
+        #[arg(name = "NAME")]
+        /// Instance name
+        name: String,
+    },
+
+    #[command(
+        name = "up",
+        about = "Create and start the instances in an environment file"
+    )]
+    Up {
+        #[arg(short, long = "file")]
+        /// Environment file
+        file: String,
+
+        #[arg(long)]
+        /// Wait until all the instances are running
+        wait: bool,
+    },
+
+    #[command(name = "volume", about = "Create, attach and resize EBS volumes")]
+    Volume {
+        #[command(subcommand)]
+        command: VolumeCommands,
+    },
+
     #[command(
         name = "generate-completions",
         about = "Generate command-line completions\n\nExample:\n   aws-instance generate-completions zsh > ~/.zsh_completions/_aws-instance"
@@ -212,46 +505,524 @@ pub enum SubCommands {
     },
 }
 
+#[derive(Debug, Parser)]
+pub enum ConfigCommands {
+    #[command(name = "show", about = "Show the effective settings for a profile")]
+    Show {
+        #[arg(long)]
+        /// Show which file and line, or environment variable, each value came from
+        origin: bool,
+    },
+
+    #[command(name = "set", about = "Set a value in a profile")]
+    Set {
+        #[arg(name = "KEY")]
+        /// Setting name, e.g. keypair
+        key: String,
+
+        #[arg(name = "VALUE")]
+        /// Value to set
+        value: String,
+    },
+
+    #[command(name = "unset", about = "Remove a value from a profile")]
+    Unset {
+        #[arg(name = "KEY")]
+        /// Setting name, e.g. keypair
+        key: String,
+    },
+
+    #[command(name = "list-profiles", about = "List the available profiles")]
+    ListProfiles,
+
+    #[command(
+        name = "validate",
+        about = "Check the keypair, security groups and region in a profile exist"
+    )]
+    Validate,
+}
+
+#[derive(Debug, Parser)]
+pub enum SgCommands {
+    #[command(name = "create", about = "Create a security group")]
+    Create {
+        #[arg(name = "NAME")]
+        /// Security group name
+        name: String,
+
+        #[arg(long = "vpc")]
+        /// VPC to create the group in [default: the default VPC]
+        vpc_id: Option<String>,
+
+        #[arg(long)]
+        /// Description of the group [default: the name]
+        description: Option<String>,
+    },
+
+    #[command(name = "allow", about = "Add a rule to a security group")]
+    Allow {
+        #[command(flatten)]
+        rule: RuleOptions,
+    },
+
+    #[command(name = "revoke", about = "Remove a rule from a security group")]
+    Revoke {
+        #[command(flatten)]
+        rule: RuleOptions,
+    },
+
+    #[command(
+        name = "allow-me",
+        about = "Let this machine's public IP address in to a security group"
+    )]
+    AllowMe {
+        #[arg(name = "GROUP")]
+        /// Security group ID or name
+        group: String,
+
+        #[arg(long, default_value = "22", value_parser = PortRange::from_str)]
+        /// Port, range of ports (e.g. 8000-8100) or all
+        port: PortRange,
+    },
+
+    #[command(
+        name = "prune-me",
+        about = "Remove rules added by allow-me for addresses you no longer have"
+    )]
+    PruneMe {
+        #[arg(name = "GROUP")]
+        /// Security group ID or name
+        group: String,
+
+        #[arg(long)]
+        /// Remove the rule for the current address too
+        all: bool,
+    },
+
+    #[command(
+        name = "unused",
+        about = "List security groups which nothing uses, and optionally delete them"
+    )]
+    Unused {
+        #[arg(long)]
+        /// Delete the unused groups
+        delete: bool,
+
+        #[arg(long, short)]
+        /// Don't ask for confirmation
+        yes: bool,
+    },
+
+    #[command(name = "export", about = "Write a security group's rules out as TOML")]
+    Export {
+        #[arg(name = "GROUP")]
+        /// Security group ID or name
+        group: String,
+    },
+
+    #[command(
+        name = "apply",
+        about = "Change a security group's rules to match a TOML file"
+    )]
+    Apply {
+        #[arg(name = "FILE")]
+        /// File written by sg export
+        file: String,
+
+        #[arg(long)]
+        /// Only show the changes which would be made
+        plan: bool,
+    },
+
+    #[command(name = "delete", about = "Delete a security group")]
+    Delete {
+        #[arg(name = "GROUP")]
+        /// Security group ID or name
+        group: String,
+
+        #[arg(long, short)]
+        /// Don't ask for confirmation
+        yes: bool,
+    },
+}
+
+#[derive(Debug, clap::Args)]
+#[command(group(clap::ArgGroup::new("source").required(true).args(["cidr", "from_group", "my_ip"])))]
+pub struct RuleOptions {
+    #[arg(name = "GROUP")]
+    /// Security group ID or name
+    group: String,
+
+    #[arg(long)]
+    /// Port, range of ports (e.g. 8000-8100) or all [default: 22]. For icmp, the type and
+    /// code as TYPE, TYPE-CODE or all [default: all]
+    port: Option<String>,
+
+    #[arg(long, default_value = "tcp")]
+    /// Protocol: tcp, udp, icmp or all
+    protocol: String,
+
+    #[arg(long)]
+    /// Allow traffic from this CIDR block
+    cidr: Option<String>,
+
+    #[arg(long = "from-group")]
+    /// Allow traffic from instances in this security group
+    from_group: Option<String>,
+
+    #[arg(long = "my-ip")]
+    /// Allow traffic from this machine's public IP address
+    my_ip: bool,
+
+    #[arg(long)]
+    /// Change an outbound rule instead of an inbound one
+    egress: bool,
+
+    #[arg(long)]
+    /// Description of the rule
+    description: Option<String>,
+}
+
+impl RuleOptions {
+    async fn rule(&self, client: &Ec2Client, echo_url: &str) -> Result<Rule> {
+        // ICMP rules use the port fields for the ICMP type and code
+        let ports = if is_icmp(&self.protocol) {
+            parse_icmp(self.port.as_deref().unwrap_or("all"))
+        } else {
+            PortRange::from_str(self.port.as_deref().unwrap_or("22"))
+        }
+        .map_err(|message| AwsInstanceError::Validation { message })?;
+        let source = match (&self.cidr, &self.from_group) {
+            (Some(cidr), _) => RuleSource::Cidr(cidr.clone()),
+            (None, Some(group)) => RuleSource::Group(
+                security_group::get_security_group(client, group)
+                    .await?
+                    .group_id
+                    .unwrap_or_default(),
+            ),
+            (None, None) => RuleSource::from_ip(public_ip(echo_url).await?),
+        };
+        Ok(Rule {
+            protocol: self.protocol.clone(),
+            ports,
+            source,
+            description: self.description.clone(),
+        })
+    }
+}
+
+#[derive(Debug, Parser)]
+pub enum KeypairCommands {
+    #[command(
+        name = "create",
+        about = "Create a key pair, saving its private key in ~/.aws-instance/keys"
+    )]
+    Create {
+        #[arg(name = "NAME")]
+        /// Key pair name
+        name: String,
+    },
+
+    #[command(name = "import", about = "Upload an existing public key as a key pair")]
+    Import {
+        #[arg(name = "NAME")]
+        /// Key pair name
+        name: String,
+
+        #[arg(long = "public-key")]
+        /// Path to the public key, e.g. ~/.ssh/id_ed25519.pub
+        public_key: String,
+    },
+
+    #[command(name = "list", about = "List key pairs")]
+    List,
+
+    #[command(name = "delete", about = "Delete a key pair")]
+    Delete {
+        #[arg(name = "NAME")]
+        /// Key pair name
+        name: String,
+
+        #[arg(long, short)]
+        /// Don't ask for confirmation
+        yes: bool,
+    },
+}
+
+#[derive(Debug, Parser)]
+pub enum VolumeCommands {
+    #[command(
+        name = "create",
+        about = "Create an empty volume in the same availability zone as an instance"
+    )]
+    Create {
+        #[arg(long)]
+        /// Size in GiB
+        size: i64,
+
+        #[arg(long = "type", default_value = "gp3")]
+        /// Volume type, e.g. gp3, io2 or st1
+        volume_type: String,
+
+        #[arg(long = "az-of")]
+        /// Name of the instance the volume will be attached to
+        az_of: String,
+    },
+
+    #[command(name = "attach", about = "Attach a volume to an instance")]
+    Attach {
+        #[arg(name = "VOLUME")]
+        /// Volume ID
+        volume_id: String,
+
+        #[arg(name = "NAME")]
+        /// Instance name
+        name: String,
+
+        #[arg(long)]
+        /// Device name, e.g. /dev/sdf
+        device: String,
+    },
+
+    #[command(name = "detach", about = "Detach a volume from its instance")]
+    Detach {
+        #[arg(name = "VOLUME")]
+        /// Volume ID
+        volume_id: String,
+
+        #[arg(long)]
+        /// Detach even if the instance hasn't released it, which can lose data
+        force: bool,
+    },
+
+    #[command(name = "resize", about = "Grow a volume")]
+    Resize {
+        #[arg(name = "VOLUME")]
+        /// Volume ID
+        volume_id: String,
+
+        #[arg(long)]
+        /// New size in GiB
+        size: i64,
+    },
+
+    #[command(name = "list", about = "List volumes")]
+    List {
+        #[arg(long)]
+        /// Only show the volumes attached to this instance
+        instance: Option<String>,
+    },
+}
+
+#[derive(Debug, Parser)]
+pub enum SnapshotCommands {
+    #[command(name = "list", about = "List an instance's snapshot sets")]
+    List {
+        #[arg(name = "NAME")]
+        /// Instance name
+        name: String,
+    },
+
+    #[command(
+        name = "prune",
+        about = "Delete all but the newest snapshot sets of each instance"
+    )]
+    Prune {
+        #[arg(name = "NAME")]
+        /// Only prune this instance's snapshots
+        name: Option<String>,
+
+        #[arg(long)]
+        /// Number of snapshot sets to keep
+        keep: usize,
+
+        #[arg(long, short)]
+        /// Don't ask for confirmation
+        yes: bool,
+    },
+}
+
+#[derive(Debug, Parser)]
+pub enum ImageCommands {
+    #[command(name = "list", about = "List the images this account owns")]
+    List,
+
+    #[command(name = "create", about = "Capture an AMI from an instance")]
+    Create {
+        #[arg(name = "NAME")]
+        /// Instance name
+        name: String,
+
+        #[arg(long = "image-name")]
+        /// Name for the new image
+        image_name: String,
+
+        #[arg(long = "no-reboot")]
+        /// Don't reboot the instance first. The image may not be consistent
+        no_reboot: bool,
+
+        #[arg(long)]
+        /// Wait until the image is available
+        wait: bool,
+    },
+
+    #[command(name = "delete", about = "Deregister an AMI and delete its snapshots")]
+    Delete {
+        #[arg(name = "AMI")]
+        /// AMI Image ID
+        image_id: String,
+
+        #[arg(long, short)]
+        /// Don't ask for confirmation
+        yes: bool,
+    },
+
+    #[command(name = "copy", about = "Copy an AMI to another region")]
+    Copy {
+        #[arg(name = "AMI")]
+        /// AMI Image ID
+        image_id: String,
+
+        #[arg(long = "to-region")]
+        /// Region to copy the image to
+        to_region: String,
+
+        #[arg(long = "image-name")]
+        /// Name for the copy [default: the same name]
+        image_name: Option<String>,
+    },
+
+    #[command(name = "share", about = "Let other accounts launch an AMI")]
+    Share {
+        #[arg(name = "AMI")]
+        /// AMI Image ID
+        image_id: String,
+
+        #[arg(long = "account", required = true)]
+        /// Account ID to share with
+        accounts: Vec<String>,
+
+        #[arg(long)]
+        /// Stop sharing with these accounts instead
+        remove: bool,
+    },
+}
+
 pub fn parse_command_line() -> CmdLineOptions {
     CmdLineOptions::parse()
 }
 
-impl SubCommands {
-    pub async fn run(&self, client: &Ec2Client, profile: Profile) -> Result<()> {
+impl Ec2Commands {
+    pub async fn run(
+        &self,
+        client: &Ec2Client,
+        config_file: &ConfigFileReader,
+        profile: Profile,
+        dry_run: bool,
+    ) -> Result<()> {
         match self {
-            SubCommands::List { .. } => {
+            Ec2Commands::Keypair { command } => {
+                command.run(client, dry_run).await?;
+            }
+
+            Ec2Commands::List { .. } => {
                 self.list(client).await?;
             }
 
-            SubCommands::ListAmis { .. } => {
-                self.list_amis(client).await?;
+            Ec2Commands::ListAmis { .. } => {
+                self.list_amis(client, &profile).await?;
+            }
+
+            Ec2Commands::ListLaunchTemplates { name } => {
+                list_launch_templates(client, name).await?;
             }
 
-            SubCommands::ListGroups { .. } => {
+            Ec2Commands::ListGroups { .. } => {
                 self.list_security_groups(client).await?;
             }
 
-            SubCommands::Create { .. } => {
-                self.create(client, profile).await?;
+            Ec2Commands::Create { .. } => {
+                self.create(client, config_file, profile, dry_run).await?;
             }
 
-            SubCommands::Destroy { name } => {
-                destroy_instance(client, name).await?;
+            Ec2Commands::Destroy { name, yes } => {
+                let protect_tag = profile
+                    .protect_tag
+                    .unwrap_or_else(|| DEFAULT_PROTECT_TAG.into());
+                destroy_instance(client, name, *yes, &protect_tag, dry_run).await?;
             }
 
-            SubCommands::Ssh { .. } => {
-                self.ssh(client, profile).await?;
+            Ec2Commands::Up { file, wait } => {
+                let environment = read_environment(file)?;
+                up(client, &environment, config_file, &profile, *wait, dry_run).await?;
             }
 
-            SubCommands::Start { name } => {
-                start(client, name).await?;
+            Ec2Commands::Down { file, destroy, yes } => {
+                let environment = read_environment(file)?;
+                let protect_tag = profile
+                    .protect_tag
+                    .unwrap_or_else(|| DEFAULT_PROTECT_TAG.into());
+                down(client, &environment, *destroy, *yes, &protect_tag, dry_run).await?;
             }
 
-            SubCommands::Stop { name } => {
-                stop(client, name).await?;
+            Ec2Commands::Plan {
+                file,
+                down,
+                destroy,
+            } => {
+                let environment = read_environment(file)?;
+                plan(client, &environment, config_file, &profile, *down, *destroy).await?;
             }
 
-            SubCommands::GenerateCompletions { shell } => {
+            Ec2Commands::Protect { name } => {
+                protect(client, name, true, dry_run).await?;
+            }
+
+            Ec2Commands::Unprotect { name } => {
+                protect(client, name, false, dry_run).await?;
+            }
+
+            Ec2Commands::Restore { name, label, yes } => {
+                snapshot::restore_snapshots(client, name, label, *yes, dry_run).await?;
+            }
+
+            Ec2Commands::Sg { command } => {
+                command.run(client, &profile, dry_run).await?;
+            }
+
+            Ec2Commands::Snapshot {
+                command: Some(command),
+                ..
+            } => {
+                command.run(client, dry_run).await?;
+            }
+
+            Ec2Commands::Snapshot {
+                command: None,
+                name,
+                label,
+            } => {
+                let name = name.as_deref().unwrap_or_default();
+                snapshot::create_snapshots(client, name, label.clone(), dry_run).await?;
+            }
+
+            Ec2Commands::Ssh { .. } => {
+                self.ssh(client, profile, dry_run).await?;
+            }
+
+            Ec2Commands::Start { name } => {
+                start(client, name, dry_run).await?;
+            }
+
+            Ec2Commands::Stop { name } => {
+                stop(client, name, dry_run).await?;
+            }
+
+            Ec2Commands::Volume { command } => {
+                command.run(client, dry_run).await?;
+            }
+
+            Ec2Commands::GenerateCompletions { shell } => {
                 self.generate_completions(*shell);
             }
         }
@@ -259,7 +1030,7 @@ impl SubCommands {
     }
 
     pub async fn list(&self, client: &Ec2Client) -> Result<()> {
-        if let SubCommands::List { ansible } = self {
+        if let Ec2Commands::List { ansible } = self {
             list(client, *ansible).await?;
         } else {
             panic!("Unexpected value in list: {:?}", self);
@@ -268,12 +1039,17 @@ impl SubCommands {
         Ok(())
     }
 
-    async fn list_amis(&self, client: &Ec2Client) -> Result<()> {
-        if let SubCommands::ListAmis {
+    async fn list_amis(&self, client: &Ec2Client, profile: &Profile) -> Result<()> {
+        if let Ec2Commands::ListAmis {
             name,
             architecture,
             image_id,
             search,
+            owners,
+            latest,
+            virtualization,
+            root_device,
+            refresh,
         } = self
         {
             let mut filters: HashMap<String, Vec<String>> = HashMap::new();
@@ -287,11 +1063,38 @@ impl SubCommands {
             );
             if let Some(image_id) = image_id {
                 filters.insert(
-                    "image_id".into(),
+                    "image-id".into(),
                     image_id.split(',').map(|s| s.into()).collect(),
                 );
             }
-            list_amis(client, &filters, search.clone()).await?;
+            if let Some(virtualization) = virtualization {
+                filters.insert("virtualization-type".into(), vec![virtualization.into()]);
+            }
+            if let Some(root_device) = root_device {
+                filters.insert("root-device-type".into(), vec![root_device.into()]);
+            }
+            // Image IDs are specific enough on their own, and the images could belong to anyone
+            let owners = if !owners.is_empty() {
+                owners
+                    .iter()
+                    .flat_map(|owner| owner.split(','))
+                    .map(|owner| owner.to_string())
+                    .collect()
+            } else if image_id.is_some() {
+                Vec::new()
+            } else {
+                default_owners()
+            };
+            let ami_cache = AmiCache::new(profile, *refresh);
+            list_amis(
+                client,
+                &ami_cache,
+                &owners,
+                &filters,
+                search.clone(),
+                *latest,
+            )
+            .await?;
         } else {
             panic!("Unexpected value in list_amis: {:?}", self);
         }
@@ -300,8 +1103,8 @@ impl SubCommands {
     }
 
     pub async fn list_security_groups(&self, client: &Ec2Client) -> Result<()> {
-        if let SubCommands::ListGroups { name } = self {
-            list_security_groups(client, name).await?;
+        if let Ec2Commands::ListGroups { name, usage } = self {
+            list_security_groups(client, name, *usage).await?;
         } else {
             panic!("Unexpected value in list: {:?}", self);
         }
@@ -309,39 +1112,59 @@ impl SubCommands {
         Ok(())
     }
 
-    async fn create(&self, client: &Ec2Client, profile: Profile) -> Result<()> {
-        if let SubCommands::Create {
+    async fn create(
+        &self,
+        client: &Ec2Client,
+        config_file: &ConfigFileReader,
+        profile: Profile,
+        dry_run: bool,
+    ) -> Result<()> {
+        if let Ec2Commands::Create {
             name,
+            template,
+            launch_template,
             ami_id,
+            architecture,
             ebs_optimized,
             iam_profile,
             instance_type,
             keypair_name,
             security_group_ids,
             os_name,
+            subnet,
+            volumes,
+            tags,
+            user_data,
+            protect,
+            refresh,
         } = self
         {
-            let my_security_groups =
-                if security_group_ids.is_empty() && profile.security_groups.is_some() {
-                    profile.security_groups.unwrap()
+            // Command-line options win over the template, which wins over the profile
+            let settings = CreateSettings {
+                template: template.clone(),
+                launch_template: launch_template.clone(),
+                ami_id: ami_id.clone(),
+                architecture: architecture.clone(),
+                ebs_optimized: *ebs_optimized,
+                iam_profile: iam_profile.clone(),
+                instance_type: instance_type.clone(),
+                keypair_name: keypair_name.clone(),
+                security_group_ids: if security_group_ids.is_empty() {
+                    None
                 } else {
-                    security_group_ids.clone()
-                };
+                    Some(security_group_ids.clone())
+                },
+                subnet_id: subnet.clone(),
+                os_name: os_name.clone(),
+                volumes: volumes.clone(),
+                tags: tags.clone(),
+                user_data: user_data.clone(),
+                protect: protect.then_some(true),
+            };
             create_instance(
                 client,
-                CreateOptions {
-                    name: name.clone(),
-                    ami_id: ami_id.clone(),
-                    ebs_optimized: *ebs_optimized,
-                    iam_profile: iam_profile.clone(),
-                    instance_type: instance_type
-                        .clone()
-                        .or(profile.default_instance_type)
-                        .or_else(|| Some(DEFAULT_INSTANCE_TYPE.into())),
-                    keypair_name: keypair_name.clone().or(profile.keypair),
-                    security_group_ids: my_security_groups,
-                    os_name: os_name.clone(),
-                },
+                &AmiCache::new(&profile, *refresh),
+                settings.into_options(name, config_file, &profile, dry_run)?,
             )
             .await?;
         } else {
@@ -351,14 +1174,29 @@ impl SubCommands {
         Ok(())
     }
 
-    async fn ssh(&self, client: &Ec2Client, profile: Profile) -> Result<()> {
-        if let SubCommands::Ssh {
+    async fn ssh(&self, client: &Ec2Client, profile: Profile, dry_run: bool) -> Result<()> {
+        if let Ec2Commands::Ssh {
             name,
             username,
             key,
+            open,
             sshopts,
         } = self
         {
+            if *open {
+                let instance =
+                    util::get_instance_by_name(client, name)
+                        .await?
+                        .ok_or_else(|| AwsInstanceError::InstanceNotFoundError {
+                            instance_name: name.into(),
+                        })?;
+                let echo_url = profile
+                    .ip_echo_url
+                    .as_deref()
+                    .unwrap_or(DEFAULT_IP_ECHO_URL);
+                security_group::allow_me_for_instance(client, &instance, echo_url, dry_run).await?;
+            }
+
             let mut mysshopts = sshopts.clone();
             if let Some(keypath) = key.clone().or(profile.ssh_key) {
                 if !sshopts.contains(&("-i".into())) {
@@ -379,6 +1217,207 @@ impl SubCommands {
     }
 }
 
+impl ConfigCommands {
+    /// Only `validate` talks to AWS
+    pub fn needs_client(&self) -> bool {
+        matches!(self, ConfigCommands::Validate)
+    }
+
+    pub async fn run(
+        &self,
+        config_file: &ConfigFileReader,
+        profile_name: &str,
+        client: Option<(&Ec2Client, &Profile)>,
+    ) -> Result<()> {
+        match self {
+            ConfigCommands::Show { origin } => config::show(config_file, profile_name, *origin)?,
+            ConfigCommands::Set { key, value } => {
+                config::set(config_file, profile_name, key, Some(value))?
+            }
+            ConfigCommands::Unset { key } => config::set(config_file, profile_name, key, None)?,
+            ConfigCommands::ListProfiles => config::list_profiles(config_file),
+            ConfigCommands::Validate => {
+                let (client, profile) = client.ok_or_else(|| AwsInstanceError::Validation {
+                    message: "config validate needs an EC2 client".into(),
+                })?;
+                config::validate(client, profile_name, profile).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl SgCommands {
+    pub async fn run(&self, client: &Ec2Client, profile: &Profile, dry_run: bool) -> Result<()> {
+        let echo_url = profile
+            .ip_echo_url
+            .as_deref()
+            .unwrap_or(DEFAULT_IP_ECHO_URL);
+        match self {
+            SgCommands::Create {
+                name,
+                vpc_id,
+                description,
+            } => {
+                security_group::create_security_group(
+                    client,
+                    name,
+                    vpc_id.clone(),
+                    description.clone(),
+                    dry_run,
+                )
+                .await?
+            }
+            SgCommands::Allow { rule } | SgCommands::Revoke { rule } => {
+                let allow = matches!(self, SgCommands::Allow { .. });
+                security_group::change_rule(
+                    client,
+                    &rule.group,
+                    &rule.rule(client, echo_url).await?,
+                    rule.egress,
+                    allow,
+                    dry_run,
+                )
+                .await?
+            }
+            SgCommands::AllowMe { group, port } => {
+                security_group::allow_me(client, group, *port, echo_url, dry_run).await?
+            }
+            SgCommands::PruneMe { group, all } => {
+                security_group::prune_me(client, group, echo_url, *all, dry_run).await?
+            }
+            SgCommands::Unused { delete, yes } => {
+                security_group::unused_security_groups(client, *delete, *yes, dry_run).await?
+            }
+            SgCommands::Export { group } => export_security_group(client, group).await?,
+            SgCommands::Apply { file, plan } => {
+                let file = read_security_group_file(file)?;
+                apply_security_group(client, &file, *plan, dry_run).await?
+            }
+            SgCommands::Delete { group, yes } => {
+                security_group::delete_security_group(client, group, *yes, dry_run).await?
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl KeypairCommands {
+    pub async fn run(&self, client: &Ec2Client, dry_run: bool) -> Result<()> {
+        match self {
+            KeypairCommands::Create { name } => {
+                keypair::create_keypair(client, name, dry_run).await?
+            }
+            KeypairCommands::Import { name, public_key } => {
+                keypair::import_keypair(client, name, public_key, dry_run).await?
+            }
+            KeypairCommands::List => keypair::list_keypairs(client).await?,
+            KeypairCommands::Delete { name, yes } => {
+                keypair::delete_keypair(client, name, *yes, dry_run).await?
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl VolumeCommands {
+    pub async fn run(&self, client: &Ec2Client, dry_run: bool) -> Result<()> {
+        match self {
+            VolumeCommands::Create {
+                size,
+                volume_type,
+                az_of,
+            } => volume::create_volume(client, *size, volume_type, az_of, dry_run).await?,
+            VolumeCommands::Attach {
+                volume_id,
+                name,
+                device,
+            } => volume::attach_volume(client, volume_id, name, device, dry_run).await?,
+            VolumeCommands::Detach { volume_id, force } => {
+                volume::detach_volume(client, volume_id, *force, dry_run).await?
+            }
+            VolumeCommands::Resize { volume_id, size } => {
+                volume::resize_volume(client, volume_id, *size, dry_run).await?
+            }
+            VolumeCommands::List { instance } => volume::list_volumes(client, instance).await?,
+        }
+
+        Ok(())
+    }
+}
+
+impl SnapshotCommands {
+    pub async fn run(&self, client: &Ec2Client, dry_run: bool) -> Result<()> {
+        match self {
+            SnapshotCommands::List { name } => snapshot::list_snapshots(client, name).await?,
+            SnapshotCommands::Prune { name, keep, yes } => {
+                snapshot::prune_snapshots(client, name.as_deref(), *keep, *yes, dry_run).await?
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ImageCommands {
+    /// The region `copy` sends the image to, which needs its own client
+    pub fn destination_region(&self) -> Option<&str> {
+        match self {
+            ImageCommands::Copy { to_region, .. } => Some(to_region),
+            _ => None,
+        }
+    }
+
+    pub async fn run(
+        &self,
+        client: &Ec2Client,
+        region: &Region,
+        destination: Option<(&Ec2Client, &Region)>,
+        dry_run: bool,
+    ) -> Result<()> {
+        match self {
+            ImageCommands::List => image::list_images(client).await?,
+            ImageCommands::Create {
+                name,
+                image_name,
+                no_reboot,
+                wait,
+            } => image::create_image(client, name, image_name, *no_reboot, *wait, dry_run).await?,
+            ImageCommands::Delete { image_id, yes } => {
+                image::delete_image(client, image_id, *yes, dry_run).await?
+            }
+            ImageCommands::Copy {
+                image_id,
+                image_name,
+                ..
+            } => {
+                let (destination_client, destination_region) =
+                    destination.expect("copy needs a client for the destination region");
+                image::copy_image(
+                    client,
+                    region.name(),
+                    destination_client,
+                    destination_region.name(),
+                    image_id,
+                    image_name.clone(),
+                    dry_run,
+                )
+                .await?
+            }
+            ImageCommands::Share {
+                image_id,
+                accounts,
+                remove,
+            } => image::share_image(client, image_id, accounts, *remove, dry_run).await?,
+        }
+
+        Ok(())
+    }
+}
+
 fn print_completions<G: Generator>(gen: G, cmd: &mut Command) {
     generate(gen, cmd, cmd.get_name().to_string(), &mut std::io::stdout());
 }
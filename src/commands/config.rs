@@ -0,0 +1,197 @@
+use crate::commands::security_group::resolve_security_group_ids;
+use crate::ini::update_ini;
+use crate::profile::{fallback_region, ConfigFileReader, Profile, PROFILE_KEYS};
+use crate::{AwsInstanceError, Result};
+use rusoto_core::Region;
+use rusoto_ec2::{
+    DescribeKeyPairsRequest, DescribeRegionsRequest, DescribeSecurityGroupsRequest, Ec2, Ec2Client,
+};
+use std::fs;
+use std::path::Path;
+
+// Keys `config set` accepts, on top of the profile settings
+const EXTRA_KEYS: &[&str] = &["inherits"];
+
+pub fn show(config_file: &ConfigFileReader, profile_name: &str, origin: bool) -> Result<()> {
+    let values = config_file.resolve(profile_name)?;
+
+    println!("Profile: {}", profile_name);
+    for key in PROFILE_KEYS {
+        let (value, value_origin) = match values.get(*key) {
+            Some(config_value) => (config_value.value.clone(), config_value.origin.to_string()),
+            None => match (*key, fallback_region()) {
+                ("region", Some((region, variable))) => (region, format!("${}", variable)),
+                ("region", None) => (Region::default().name().into(), "default".into()),
+                _ => ("N/A".into(), "not set".into()),
+            },
+        };
+        if origin {
+            println!("  {0: <17} {1: <35} {2}", key, value, value_origin);
+        } else {
+            println!("  {0: <17} {1}", key, value);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn list_profiles(config_file: &ConfigFileReader) {
+    println!(
+        "{0: <25} {1: <12} {2: <12}",
+        "Profile", "AWS config", "Our config"
+    );
+    for (name, (in_aws_config, in_our_config)) in config_file.list_profiles() {
+        println!(
+            "{0: <25} {1: <12} {2: <12}",
+            name,
+            if in_aws_config { "yes" } else { "" },
+            if in_our_config { "yes" } else { "" }
+        );
+    }
+}
+
+pub fn set(
+    config_file: &ConfigFileReader,
+    profile_name: &str,
+    key: &str,
+    value: Option<&str>,
+) -> Result<()> {
+    let path = config_file.our_config_path();
+    if !PROFILE_KEYS.contains(&key) && !EXTRA_KEYS.contains(&key) {
+        return Err(AwsInstanceError::ConfigError {
+            path: path.display().to_string(),
+            line: None,
+            message: format!(
+                "Unknown setting '{}', expected one of: {}",
+                key,
+                [PROFILE_KEYS, EXTRA_KEYS].concat().join(", ")
+            ),
+        });
+    }
+
+    // Check the value before we write it, so we don't leave a broken config file behind
+    if let Some(value) = value {
+        Profile::default()
+            .add_value(key, value)
+            .map_err(|message| AwsInstanceError::ConfigError {
+                path: path.display().to_string(),
+                line: None,
+                message,
+            })?;
+    }
+
+    let contents = if path.exists() {
+        fs::read_to_string(path)?
+    } else {
+        String::new()
+    };
+    let updated = update_ini(&contents, path, profile_name, key, value)?;
+    write_config(path, &updated)?;
+
+    match value {
+        Some(value) => println!("Set {} = {} in profile {}", key, value, profile_name),
+        None => println!("Removed {} from profile {}", key, profile_name),
+    }
+
+    Ok(())
+}
+
+fn write_config(path: &Path, contents: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, contents)?;
+
+    Ok(())
+}
+
+/// Check the resources a profile refers to actually exist
+pub async fn validate(ec2_client: &Ec2Client, profile_name: &str, profile: &Profile) -> Result<()> {
+    let mut problems = 0;
+    let mut report = |setting: &str, result: std::result::Result<String, String>| match result {
+        Ok(message) => println!("  {0: <17} OK: {1}", setting, message),
+        Err(message) => {
+            problems += 1;
+            println!("  {0: <17} ERROR: {1}", setting, message);
+        }
+    };
+
+    println!("Profile: {}", profile_name);
+
+    let region_name = profile
+        .region
+        .clone()
+        .unwrap_or_default()
+        .name()
+        .to_string();
+    let regions = ec2_client
+        .describe_regions(DescribeRegionsRequest {
+            all_regions: Some(true),
+            ..Default::default()
+        })
+        .await?
+        .regions
+        .unwrap_or_default();
+    report(
+        "region",
+        if regions
+            .iter()
+            .any(|region| region.region_name.as_deref() == Some(&region_name))
+        {
+            Ok(region_name)
+        } else {
+            Err(format!("Region {} not found", region_name))
+        },
+    );
+
+    if let Some(ref keypair) = profile.keypair {
+        let request = DescribeKeyPairsRequest {
+            key_names: Some(vec![keypair.clone()]),
+            ..Default::default()
+        };
+        report(
+            "keypair",
+            match ec2_client.describe_key_pairs(request).await {
+                Ok(_) => Ok(keypair.clone()),
+                Err(error) => Err(AwsInstanceError::from(error).to_string()),
+            },
+        );
+    }
+
+    if let Some(ref ssh_key) = profile.ssh_key {
+        report(
+            "key",
+            if Path::new(ssh_key).is_file() {
+                Ok(ssh_key.clone())
+            } else {
+                Err(format!("File {} not found", ssh_key))
+            },
+        );
+    }
+
+    if let Some(ref security_groups) = profile.security_groups {
+        // Names are checked against the default VPC, where instances go without a subnet
+        let result = match resolve_security_group_ids(ec2_client, security_groups, None).await {
+            Ok(group_ids) => {
+                let request = DescribeSecurityGroupsRequest {
+                    group_ids: Some(group_ids.clone()),
+                    ..Default::default()
+                };
+                match ec2_client.describe_security_groups(request).await {
+                    Ok(_) => Ok(group_ids.join(", ")),
+                    Err(error) => Err(AwsInstanceError::from(error).to_string()),
+                }
+            }
+            Err(error) => Err(error.to_string()),
+        };
+        report("security-groups", result);
+    }
+
+    if problems > 0 {
+        return Err(AwsInstanceError::Validation {
+            message: format!("Found {} problem(s) in profile {}", problems, profile_name),
+        });
+    }
+
+    Ok(())
+}
@@ -1,62 +1,432 @@
+use crate::ami_cache::AmiCache;
+use crate::commands::list_amis::find_latest_ami;
+use crate::commands::security_group::resolve_security_group_ids;
+use crate::profile::{ConfigFileReader, Profile};
+use crate::template::{get_template, Template};
 use crate::{cmdline::OsNames, util, AwsInstanceError, Result};
 use rusoto_ec2::{
-    Ec2, Ec2Client, IamInstanceProfileSpecification, Reservation, RunInstancesRequest, Tag,
-    TagSpecification,
+    BlockDeviceMapping, DescribeLaunchTemplateVersionsRequest, EbsBlockDevice, Ec2, Ec2Client,
+    IamInstanceProfileSpecification, LaunchTemplateSpecification, Reservation,
+    ResponseLaunchTemplateData, RunInstancesRequest, Tag, TagSpecification,
 };
+use std::collections::BTreeMap;
+use std::fs;
+
+const DEFAULT_ARCHITECTURE: &str = "x86_64";
+const DEFAULT_INSTANCE_TYPE: &str = "m1.small";
+
+/// An EBS volume to attach at launch, written as `DEVICE:SIZE[:TYPE]`, e.g. `/dev/sdf:100:gp3`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Volume {
+    pub device: String,
+    pub size: i64,
+    pub volume_type: Option<String>,
+}
+
+impl std::str::FromStr for Volume {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let error = || format!("Invalid volume '{}', expected DEVICE:SIZE[:TYPE]", s);
+        let mut parts = s.split(':');
+        let device = parts.next().filter(|d| !d.is_empty()).ok_or_else(error)?;
+        let size = parts
+            .next()
+            .and_then(|size| size.parse().ok())
+            .ok_or_else(error)?;
+        let volume_type = parts.next().map(|t| t.to_string());
+        if parts.next().is_some() {
+            return Err(error());
+        }
+
+        Ok(Volume {
+            device: device.to_string(),
+            size,
+            volume_type,
+        })
+    }
+}
+
+/// An EC2 launch template, written as `NAME|ID[:VERSION]`, e.g. `web:3` or `lt-0abcd1234:$Latest`
+#[derive(Clone, Debug, PartialEq)]
+pub struct LaunchTemplateRef {
+    pub id_or_name: String,
+    pub version: Option<String>,
+}
+
+impl std::str::FromStr for LaunchTemplateRef {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (id_or_name, version) = match s.rsplit_once(':') {
+            Some((id_or_name, version)) => (id_or_name, Some(version.to_string())),
+            None => (s, None),
+        };
+        if id_or_name.is_empty() || version.as_deref() == Some("") {
+            return Err(format!(
+                "Invalid launch template '{}', expected NAME|ID[:VERSION]",
+                s
+            ));
+        }
+
+        Ok(LaunchTemplateRef {
+            id_or_name: id_or_name.to_string(),
+            version,
+        })
+    }
+}
+
+impl LaunchTemplateRef {
+    fn specification(&self) -> LaunchTemplateSpecification {
+        let is_id = self.id_or_name.starts_with("lt-");
+        LaunchTemplateSpecification {
+            launch_template_id: is_id.then(|| self.id_or_name.clone()),
+            launch_template_name: (!is_id).then(|| self.id_or_name.clone()),
+            version: self.version.clone(),
+        }
+    }
+
+    /// The settings in the version of the launch template that would be used
+    async fn data(&self, ec2_client: &Ec2Client) -> Result<ResponseLaunchTemplateData> {
+        let specification = self.specification();
+        let request = DescribeLaunchTemplateVersionsRequest {
+            launch_template_id: specification.launch_template_id,
+            launch_template_name: specification.launch_template_name,
+            versions: Some(vec![self
+                .version
+                .clone()
+                .unwrap_or_else(|| "$Default".into())]),
+            ..Default::default()
+        };
+        ec2_client
+            .describe_launch_template_versions(request)
+            .await?
+            .launch_template_versions
+            .unwrap_or_default()
+            .into_iter()
+            .next()
+            .and_then(|version| version.launch_template_data)
+            .ok_or_else(|| AwsInstanceError::Validation {
+                message: format!("Launch template {} not found", self.id_or_name),
+            })
+    }
+}
+
+/// Parse a tag written as `KEY=VALUE`
+pub fn parse_tag(s: &str) -> std::result::Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("Invalid tag '{}', expected KEY=VALUE", s)),
+    }
+}
+
+/// Settings for a new instance, which may come from the command line, an environment file
+/// or a template. Anything left unset is filled in from the template, then the profile.
+#[derive(Clone, Debug, Default)]
+pub struct CreateSettings {
+    pub template: Option<String>,
+    pub launch_template: Option<LaunchTemplateRef>,
+    pub ami_id: Option<String>,
+    pub architecture: Option<String>,
+    pub ebs_optimized: Option<bool>,
+    pub iam_profile: Option<String>,
+    pub instance_type: Option<String>,
+    pub keypair_name: Option<String>,
+    pub security_group_ids: Option<Vec<String>>,
+    pub subnet_id: Option<String>,
+    pub os_name: Option<OsNames>,
+    pub volumes: Vec<Volume>,
+    pub tags: Vec<(String, String)>,
+    pub user_data: Option<String>,
+    pub protect: Option<bool>,
+}
+
+impl CreateSettings {
+    /// Fill in anything not set here from `other`. Volumes are merged by device, and tags by key.
+    pub fn or(self, other: CreateSettings) -> CreateSettings {
+        let mut volumes: BTreeMap<String, Volume> = other
+            .volumes
+            .into_iter()
+            .map(|volume| (volume.device.clone(), volume))
+            .collect();
+        volumes.extend(
+            self.volumes
+                .into_iter()
+                .map(|volume| (volume.device.clone(), volume)),
+        );
+        let mut tags: BTreeMap<String, String> = other.tags.into_iter().collect();
+        tags.extend(self.tags);
+
+        CreateSettings {
+            template: self.template.or(other.template),
+            launch_template: self.launch_template.or(other.launch_template),
+            ami_id: self.ami_id.or(other.ami_id),
+            architecture: self.architecture.or(other.architecture),
+            ebs_optimized: self.ebs_optimized.or(other.ebs_optimized),
+            iam_profile: self.iam_profile.or(other.iam_profile),
+            instance_type: self.instance_type.or(other.instance_type),
+            keypair_name: self.keypair_name.or(other.keypair_name),
+            security_group_ids: self.security_group_ids.or(other.security_group_ids),
+            subnet_id: self.subnet_id.or(other.subnet_id),
+            os_name: self.os_name.or(other.os_name),
+            volumes: volumes.into_values().collect(),
+            tags: tags.into_iter().collect(),
+            user_data: self.user_data.or(other.user_data),
+            protect: self.protect.or(other.protect),
+        }
+    }
+
+    /// Apply the template and profile to get the options to create `name` with. With a launch
+    /// template, the profile and built-in defaults are held back until we know which settings
+    /// the launch template leaves out, so they don't override it.
+    pub fn into_options(
+        self,
+        name: &str,
+        config_file: &ConfigFileReader,
+        profile: &Profile,
+        dry_run: bool,
+    ) -> Result<CreateOptions> {
+        let settings = match self.template {
+            Some(ref template_name) => {
+                let template = get_template(template_name, config_file)?;
+                self.or(template.into())
+            }
+            None => self,
+        };
+        let (profile, launch_template_defaults) = match settings.launch_template {
+            Some(_) => (Profile::default(), Some(profile.clone())),
+            None => (profile.clone(), None),
+        };
+        let default_instance_type = match settings.launch_template {
+            Some(_) => None,
+            None => Some(DEFAULT_INSTANCE_TYPE.into()),
+        };
+
+        Ok(CreateOptions {
+            name: name.to_string(),
+            ami_id: settings.ami_id,
+            architecture: settings.architecture,
+            ebs_optimized: settings.ebs_optimized,
+            iam_profile: settings.iam_profile,
+            instance_type: settings
+                .instance_type
+                .or(profile.default_instance_type)
+                .or(default_instance_type),
+            keypair_name: settings.keypair_name.or(profile.keypair),
+            security_group_ids: settings
+                .security_group_ids
+                .or(profile.security_groups)
+                .unwrap_or_default(),
+            launch_template: settings.launch_template,
+            launch_template_defaults,
+            subnet_id: settings.subnet_id,
+            os_name: settings.os_name,
+            volumes: settings.volumes,
+            tags: settings.tags,
+            user_data: settings.user_data,
+            protect: settings.protect.unwrap_or(false),
+            dry_run,
+        })
+    }
+}
+
+impl From<Template> for CreateSettings {
+    fn from(template: Template) -> Self {
+        CreateSettings {
+            ami_id: template.ami_id,
+            launch_template: template.launch_template,
+            architecture: template.architecture,
+            ebs_optimized: template.ebs_optimized,
+            iam_profile: template.iam_profile,
+            instance_type: template.instance_type,
+            security_group_ids: template.security_groups,
+            subnet_id: template.subnet_id,
+            os_name: template.os_name,
+            volumes: template.volumes,
+            tags: template.tags,
+            user_data: template.user_data,
+            ..Default::default()
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct CreateOptions {
     pub name: String,
-    pub ami_id: String,
-    pub ebs_optimized: bool,
+    pub ami_id: Option<String>,
+    pub architecture: Option<String>,
+    pub ebs_optimized: Option<bool>,
     pub iam_profile: Option<String>,
     pub instance_type: Option<String>,
     pub keypair_name: Option<String>,
     pub security_group_ids: Vec<String>,
+    pub subnet_id: Option<String>,
     pub os_name: Option<OsNames>,
+    pub volumes: Vec<Volume>,
+    pub tags: Vec<(String, String)>,
+    pub user_data: Option<String>,
+    pub launch_template: Option<LaunchTemplateRef>,
+    /// Profile settings to use for anything the launch template doesn't set
+    pub launch_template_defaults: Option<Profile>,
+    pub protect: bool,
+    pub dry_run: bool,
+}
+
+impl CreateOptions {
+    /// Fill in the profile's settings (and the default instance type) for whatever neither
+    /// these options nor the launch template set
+    fn or_profile(&mut self, data: &ResponseLaunchTemplateData, profile: Profile) {
+        if self.instance_type.is_none() && data.instance_type.is_none() {
+            self.instance_type = profile
+                .default_instance_type
+                .or_else(|| Some(DEFAULT_INSTANCE_TYPE.into()));
+        }
+        if self.keypair_name.is_none() && data.key_name.is_none() {
+            self.keypair_name = profile.keypair;
+        }
+        // Security groups can't be given alongside a launch template's network interfaces
+        let template_groups = data.security_group_ids.is_some()
+            || data.security_groups.is_some()
+            || data.network_interfaces.is_some();
+        if self.security_group_ids.is_empty() && !template_groups {
+            self.security_group_ids = profile.security_groups.unwrap_or_default();
+        }
+    }
 }
 
 pub async fn create_instance(
     ec2_client: &Ec2Client,
-    options: CreateOptions,
-) -> Result<Reservation> {
+    ami_cache: &AmiCache,
+    mut options: CreateOptions,
+) -> Result<Option<Reservation>> {
     match util::get_instance_by_name(ec2_client, &options.name).await? {
         Some(_) => Err(AwsInstanceError::CreateInstanceError {
             instance_name: options.name,
             message: "Instance with that name already exists".into(),
         }),
         None => {
-            let iam_instance_profile = IamInstanceProfileSpecification {
-                name: options.iam_profile,
-                ..Default::default()
+            if let (Some(launch_template), Some(profile)) = (
+                options.launch_template.clone(),
+                options.launch_template_defaults.take(),
+            ) {
+                let data = launch_template.data(ec2_client).await?;
+                options.or_profile(&data, profile);
+            }
+
+            let ami_id = match (options.ami_id, &options.os_name) {
+                (Some(ami_id), _) => Some(ami_id),
+                (None, Some(os_name)) => {
+                    let architecture = options
+                        .architecture
+                        .as_deref()
+                        .unwrap_or(DEFAULT_ARCHITECTURE);
+                    Some(
+                        find_latest_ami(ec2_client, ami_cache, os_name, architecture)
+                            .await?
+                            .ok_or_else(|| AwsInstanceError::CreateInstanceError {
+                                instance_name: options.name.clone(),
+                                message: format!("No {} AMI found for {}", architecture, os_name),
+                            })?,
+                    )
+                }
+                // The launch template supplies the AMI
+                (None, None) if options.launch_template.is_some() => None,
+                (None, None) => {
+                    return Err(AwsInstanceError::Validation {
+                        message:
+                            "No AMI given: use --ami-id, --os-name or a template which sets one"
+                                .into(),
+                    })
+                }
             };
-            let mut tags = vec![Tag {
-                key: Some("Name".to_string()),
-                value: Some(options.name.to_string()),
-            }];
-            if options.os_name.is_some() {
-                tags.push(Tag {
-                    key: Some("OS".to_string()),
-                    value: Some(options.os_name.unwrap().to_string()),
-                });
+            let user_data = match options.user_data {
+                Some(ref path) => Some(base64::encode(fs::read(path).map_err(|error| {
+                    AwsInstanceError::Validation {
+                        message: format!("Error reading user data file {}: {}", path, error),
+                    }
+                })?)),
+                None => None,
+            };
+
+            let iam_instance_profile =
+                options
+                    .iam_profile
+                    .map(|name| IamInstanceProfileSpecification {
+                        name: Some(name),
+                        ..Default::default()
+                    });
+            let mut tag_values: BTreeMap<String, String> = options.tags.into_iter().collect();
+            tag_values.insert("Name".to_string(), options.name.to_string());
+            if let Some(os_name) = options.os_name {
+                tag_values.insert("OS".to_string(), os_name.to_string());
             }
+            let tags = tag_values
+                .into_iter()
+                .map(|(key, value)| Tag {
+                    key: Some(key),
+                    value: Some(value),
+                })
+                .collect();
             let name_tag_spec = TagSpecification {
                 resource_type: Some("instance".to_string()),
                 tags: Some(tags),
             };
+            let block_device_mappings: Vec<BlockDeviceMapping> = options
+                .volumes
+                .into_iter()
+                .map(|volume| BlockDeviceMapping {
+                    device_name: Some(volume.device),
+                    ebs: Some(EbsBlockDevice {
+                        delete_on_termination: Some(true),
+                        volume_size: Some(volume.size),
+                        volume_type: volume.volume_type,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                })
+                .collect();
+            let security_group_ids = resolve_security_group_ids(
+                ec2_client,
+                &options.security_group_ids,
+                options.subnet_id.as_deref(),
+            )
+            .await?;
             let request = RunInstancesRequest {
                 min_count: 1,
                 max_count: 1,
-                image_id: Some(options.ami_id),
-                ebs_optimized: Some(options.ebs_optimized),
-                iam_instance_profile: Some(iam_instance_profile),
+                image_id: ami_id,
+                block_device_mappings: if block_device_mappings.is_empty() {
+                    None
+                } else {
+                    Some(block_device_mappings)
+                },
+                client_token: Some(util::client_token()),
+                // Only send flags which are set, so they don't override a launch template
+                disable_api_termination: options.protect.then_some(true),
+                dry_run: Some(options.dry_run),
+                ebs_optimized: options.ebs_optimized,
+                iam_instance_profile,
                 instance_type: options.instance_type,
                 key_name: options.keypair_name,
-                security_group_ids: Some(options.security_group_ids),
+                launch_template: options
+                    .launch_template
+                    .as_ref()
+                    .map(LaunchTemplateRef::specification),
+                security_group_ids: if security_group_ids.is_empty() {
+                    None
+                } else {
+                    Some(security_group_ids)
+                },
+                subnet_id: options.subnet_id,
                 tag_specifications: Some(vec![name_tag_spec]),
+                user_data,
                 ..Default::default()
             };
-            Ok(ec2_client.run_instances(request).await?)
+            match ec2_client.run_instances(request).await {
+                Ok(reservation) => Ok(Some(reservation)),
+                Err(error) => util::dry_run_ok(error.into(), &format!("create {}", options.name))
+                    .map(|_| None),
+            }
         }
     }
 }
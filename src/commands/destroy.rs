@@ -1,11 +1,54 @@
+use crate::commands::protect::is_termination_protected;
 use crate::{print_state_changes, util, AwsInstanceError, Result};
 use rusoto_ec2::{Ec2, Ec2Client};
 
-pub async fn destroy_instance(ec2_client: &Ec2Client, name: &str) -> Result<()> {
+pub const DEFAULT_PROTECT_TAG: &str = "do-not-delete";
+
+pub async fn destroy_instance(
+    ec2_client: &Ec2Client,
+    name: &str,
+    yes: bool,
+    protect_tag: &str,
+    dry_run: bool,
+) -> Result<()> {
     match util::get_instance_by_name(ec2_client, name).await? {
         Some(instance) => {
+            if util::has_tag(&instance, protect_tag) {
+                return Err(AwsInstanceError::DestroyInstanceError {
+                    instance_name: name.into(),
+                    message: format!("Instance is tagged with '{}'", protect_tag),
+                });
+            }
+
             let instance_id = instance.instance_id.unwrap();
 
+            // Check this before renaming, so a protected instance is left untouched
+            if is_termination_protected(ec2_client, &instance_id).await? {
+                return Err(AwsInstanceError::DestroyInstanceError {
+                    instance_name: name.into(),
+                    message: format!(
+                        "Termination protection is enabled, run 'aws-instance unprotect {}' first",
+                        name
+                    ),
+                });
+            }
+
+            if !yes
+                && !dry_run
+                && !util::confirm(
+                    &format!(
+                        "This will terminate {} ({}). Type the instance name to confirm: ",
+                        name, instance_id
+                    ),
+                    name,
+                )?
+            {
+                return Err(AwsInstanceError::DestroyInstanceError {
+                    instance_name: name.into(),
+                    message: "Confirmation did not match instance name".into(),
+                });
+            }
+
             // Change the instance name tag
             let tag_request = rusoto_ec2::CreateTagsRequest {
                 resources: vec![instance_id.clone()],
@@ -13,16 +56,26 @@ pub async fn destroy_instance(ec2_client: &Ec2Client, name: &str) -> Result<()>
                     key: Some("Name".into()),
                     value: Some(format!("{}-terminated", name)),
                 }],
-                ..Default::default()
+                dry_run: Some(dry_run),
             };
-            ec2_client.create_tags(tag_request).await?;
+            if let Err(error) = ec2_client.create_tags(tag_request).await {
+                util::dry_run_ok(
+                    error.into(),
+                    &format!("rename {} to {}-terminated", name, name),
+                )?;
+            }
 
             // Terminate the instance
             let request = rusoto_ec2::TerminateInstancesRequest {
                 instance_ids: vec![instance_id],
-                ..Default::default()
+                dry_run: Some(dry_run),
+            };
+            let result = match ec2_client.terminate_instances(request).await {
+                Ok(result) => result,
+                Err(error) => {
+                    return util::dry_run_ok(error.into(), &format!("terminate {}", name))
+                }
             };
-            let result = ec2_client.terminate_instances(request).await?;
 
             // Print the state change
             if let Some(state_changes) = result.terminating_instances {
@@ -35,9 +88,8 @@ pub async fn destroy_instance(ec2_client: &Ec2Client, name: &str) -> Result<()>
             }
         }
         None => {
-            return Err(AwsInstanceError::DestroyInstanceError {
+            return Err(AwsInstanceError::InstanceNotFoundError {
                 instance_name: name.into(),
-                message: "Instance not found".into(),
             })
         }
     }
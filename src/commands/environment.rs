@@ -0,0 +1,361 @@
+use crate::ami_cache::AmiCache;
+use crate::commands::create::{
+    create_instance, parse_tag, CreateSettings, LaunchTemplateRef, Volume,
+};
+use crate::commands::destroy::destroy_instance;
+use crate::commands::start::start;
+use crate::commands::stop::stop;
+use crate::profile::{ConfigFileReader, Profile};
+use crate::{cmdline::OsNames, util, AwsInstanceError, Result};
+use rusoto_ec2::{Ec2Client, Instance};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+const WAIT_INTERVAL: Duration = Duration::from_secs(5);
+const WAIT_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// An environment file: a set of named instances, plus defaults shared between them
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Environment {
+    #[serde(default)]
+    pub defaults: EnvironmentInstance,
+    #[serde(default)]
+    pub instances: BTreeMap<String, EnvironmentInstance>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct EnvironmentInstance {
+    pub template: Option<String>,
+    pub ami_id: Option<String>,
+    pub launch_template: Option<String>,
+    pub os_name: Option<String>,
+    pub architecture: Option<String>,
+    pub instance_type: Option<String>,
+    pub keypair: Option<String>,
+    pub security_groups: Option<Vec<String>>,
+    pub subnet: Option<String>,
+    #[serde(default)]
+    pub volumes: Vec<String>,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    pub user_data: Option<String>,
+    pub iam_profile: Option<String>,
+    pub ebs_optimized: Option<bool>,
+    pub protect: Option<bool>,
+}
+
+impl EnvironmentInstance {
+    fn settings(&self) -> std::result::Result<CreateSettings, String> {
+        Ok(CreateSettings {
+            template: self.template.clone(),
+            ami_id: self.ami_id.clone(),
+            launch_template: match self.launch_template {
+                Some(ref launch_template) => Some(LaunchTemplateRef::from_str(launch_template)?),
+                None => None,
+            },
+            architecture: self.architecture.clone(),
+            ebs_optimized: self.ebs_optimized,
+            iam_profile: self.iam_profile.clone(),
+            instance_type: self.instance_type.clone(),
+            keypair_name: self.keypair.clone(),
+            security_group_ids: self.security_groups.clone(),
+            subnet_id: self.subnet.clone(),
+            os_name: match self.os_name {
+                Some(ref os_name) => {
+                    Some(OsNames::from_str(os_name).map_err(|error| error.to_string())?)
+                }
+                None => None,
+            },
+            volumes: self
+                .volumes
+                .iter()
+                .map(|volume| Volume::from_str(volume))
+                .collect::<std::result::Result<_, _>>()?,
+            tags: self
+                .tags
+                .iter()
+                .map(|(key, value)| parse_tag(&format!("{}={}", key, value)))
+                .collect::<std::result::Result<_, _>>()?,
+            user_data: self.user_data.clone(),
+            protect: self.protect,
+        })
+    }
+}
+
+/// What needs doing to an instance to get it to the state the environment file asks for
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    Create,
+    Start,
+    Stop,
+    Destroy,
+    Nothing,
+    /// The instance is changing state, so leave it alone
+    Skip(String),
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Action::Create => write!(f, "create"),
+            Action::Start => write!(f, "start"),
+            Action::Stop => write!(f, "stop"),
+            Action::Destroy => write!(f, "destroy"),
+            Action::Nothing => write!(f, "-"),
+            Action::Skip(state) => write!(f, "skip ({})", state),
+        }
+    }
+}
+
+pub fn read_environment(path: &str) -> Result<Environment> {
+    let contents = fs::read_to_string(path).map_err(|error| AwsInstanceError::ConfigError {
+        path: path.into(),
+        line: None,
+        message: format!("Error reading file: {}", error),
+    })?;
+
+    toml::from_str(&contents).map_err(|error| AwsInstanceError::ConfigError {
+        path: path.into(),
+        line: error.line_col().map(|(line, _)| line + 1),
+        message: error.to_string(),
+    })
+}
+
+/// The instances which aren't terminated, by name
+async fn current_instances(ec2_client: &Ec2Client) -> Result<HashMap<String, Instance>> {
+    Ok(util::get_all_instances(ec2_client)
+        .await?
+        .into_iter()
+        .filter(|instance| util::get_state(instance) != "terminated")
+        .map(|instance| (util::get_name(&instance), instance))
+        .collect())
+}
+
+fn up_action(instance: Option<&Instance>) -> Action {
+    match instance.map(util::get_state).as_deref() {
+        None => Action::Create,
+        Some("stopped") => Action::Start,
+        Some("running") | Some("pending") => Action::Nothing,
+        Some(state) => Action::Skip(state.into()),
+    }
+}
+
+fn down_action(instance: Option<&Instance>, destroy: bool) -> Action {
+    match (instance.map(util::get_state).as_deref(), destroy) {
+        (None, _) => Action::Nothing,
+        (Some("running") | Some("pending") | Some("stopped"), true) => Action::Destroy,
+        (Some("running") | Some("pending"), false) => Action::Stop,
+        (Some("stopped"), false) => Action::Nothing,
+        (Some(state), _) => Action::Skip(state.into()),
+    }
+}
+
+/// Settings which differ between the environment file and a running instance
+fn drift(
+    name: &str,
+    wanted: &EnvironmentInstance,
+    environment: &Environment,
+    instance: &Instance,
+    config_file: &ConfigFileReader,
+    profile: &Profile,
+) -> Result<Vec<String>> {
+    let options =
+        settings_for(name, wanted, environment)?.into_options(name, config_file, profile, false)?;
+
+    let mut differences = vec![];
+    if let Some(ref instance_type) = options.instance_type {
+        if instance.instance_type.as_ref() != Some(instance_type) {
+            differences.push(format!(
+                "instance-type {} (wanted {})",
+                instance.instance_type.as_deref().unwrap_or("N/A"),
+                instance_type
+            ));
+        }
+    }
+    if let Some(ref ami_id) = options.ami_id {
+        if instance.image_id.as_ref() != Some(ami_id) {
+            differences.push(format!(
+                "ami-id {} (wanted {})",
+                instance.image_id.as_deref().unwrap_or("N/A"),
+                ami_id
+            ));
+        }
+    }
+    if let Some(ref subnet_id) = options.subnet_id {
+        if instance.subnet_id.as_ref() != Some(subnet_id) {
+            differences.push(format!(
+                "subnet {} (wanted {})",
+                instance.subnet_id.as_deref().unwrap_or("N/A"),
+                subnet_id
+            ));
+        }
+    }
+
+    Ok(differences)
+}
+
+fn settings_for(
+    name: &str,
+    wanted: &EnvironmentInstance,
+    environment: &Environment,
+) -> Result<CreateSettings> {
+    let error = |message: String| AwsInstanceError::Validation {
+        message: format!("Instance {}: {}", name, message),
+    };
+    Ok(wanted
+        .settings()
+        .map_err(error)?
+        .or(environment.defaults.settings().map_err(error)?))
+}
+
+/// Show what `up` (or `down`) would do, and any settings which have drifted
+pub async fn plan(
+    ec2_client: &Ec2Client,
+    environment: &Environment,
+    config_file: &ConfigFileReader,
+    profile: &Profile,
+    down: bool,
+    destroy: bool,
+) -> Result<()> {
+    let current = current_instances(ec2_client).await?;
+
+    println!(
+        "{0: <25} {1: <15} {2: <15} Drift",
+        "Name", "State", "Action"
+    );
+    for (name, wanted) in &environment.instances {
+        let instance = current.get(name);
+        let action = if down {
+            down_action(instance, destroy)
+        } else {
+            up_action(instance)
+        };
+        let differences = match instance {
+            Some(instance) => drift(name, wanted, environment, instance, config_file, profile)?,
+            None => vec![],
+        };
+        println!(
+            "{0: <25} {1: <15} {2: <15} {3}",
+            name,
+            instance
+                .map(util::get_state)
+                .unwrap_or_else(|| "missing".into()),
+            action.to_string(),
+            differences.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Create the missing instances in an environment and start the stopped ones
+pub async fn up(
+    ec2_client: &Ec2Client,
+    environment: &Environment,
+    config_file: &ConfigFileReader,
+    profile: &Profile,
+    wait: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let current = current_instances(ec2_client).await?;
+
+    // Work out all the settings first, so a mistake in the file doesn't leave a half-built
+    // environment behind
+    let mut actions = vec![];
+    for (name, wanted) in &environment.instances {
+        let action = up_action(current.get(name));
+        let options = match action {
+            Action::Create => Some(settings_for(name, wanted, environment)?.into_options(
+                name,
+                config_file,
+                profile,
+                dry_run,
+            )?),
+            _ => None,
+        };
+        actions.push((name, action, options));
+    }
+
+    let ami_cache = AmiCache::new(profile, false);
+    for (name, action, options) in actions {
+        match (action, options) {
+            (Action::Create, Some(options)) => {
+                println!("Creating {}", name);
+                create_instance(ec2_client, &ami_cache, options).await?;
+            }
+            (Action::Start, _) => {
+                println!("Starting {}", name);
+                start(ec2_client, name, dry_run).await?;
+            }
+            (Action::Skip(state), _) => println!("Skipping {}, which is {}", name, state),
+            _ => println!("{} is already running", name),
+        }
+    }
+
+    if wait && !dry_run {
+        let names: Vec<&String> = environment.instances.keys().collect();
+        wait_for_running(ec2_client, &names).await?;
+    }
+
+    Ok(())
+}
+
+/// Stop, or destroy, the instances in an environment
+pub async fn down(
+    ec2_client: &Ec2Client,
+    environment: &Environment,
+    destroy: bool,
+    yes: bool,
+    protect_tag: &str,
+    dry_run: bool,
+) -> Result<()> {
+    let current = current_instances(ec2_client).await?;
+
+    for name in environment.instances.keys() {
+        match down_action(current.get(name), destroy) {
+            Action::Stop => {
+                println!("Stopping {}", name);
+                stop(ec2_client, name, dry_run).await?;
+            }
+            Action::Destroy => {
+                println!("Destroying {}", name);
+                destroy_instance(ec2_client, name, yes, protect_tag, dry_run).await?;
+            }
+            Action::Skip(state) => println!("Skipping {}, which is {}", name, state),
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+async fn wait_for_running(ec2_client: &Ec2Client, names: &[&String]) -> Result<()> {
+    let started = Instant::now();
+    loop {
+        let current = current_instances(ec2_client).await?;
+        let waiting: Vec<&str> = names
+            .iter()
+            .filter(|name| {
+                current.get(name.as_str()).map(util::get_state).as_deref() != Some("running")
+            })
+            .map(|name| name.as_str())
+            .collect();
+        if waiting.is_empty() {
+            println!("All instances are running");
+            return Ok(());
+        }
+        if started.elapsed() > WAIT_TIMEOUT {
+            return Err(AwsInstanceError::StartInstanceError {
+                instance_name: waiting.join(", "),
+                message: format!("Not running after {} seconds", WAIT_TIMEOUT.as_secs()),
+            });
+        }
+        println!("Waiting for {}", waiting.join(", "));
+        tokio::time::sleep(WAIT_INTERVAL).await;
+    }
+}
@@ -0,0 +1,297 @@
+use crate::commands::list_amis::AmiInfo;
+use crate::{util, AwsInstanceError, Result};
+use rusoto_ec2::{
+    CopyImageRequest, CreateImageRequest, DeleteSnapshotRequest, DeregisterImageRequest,
+    DescribeImagesRequest, Ec2, Ec2Client, Image, LaunchPermission, LaunchPermissionModifications,
+    ModifyImageAttributeRequest, Tag, TagSpecification,
+};
+use std::time::{Duration, Instant};
+
+const WAIT_INTERVAL: Duration = Duration::from_secs(15);
+const WAIT_TIMEOUT: Duration = Duration::from_secs(3600);
+
+async fn describe_image(ec2_client: &Ec2Client, image_id: &str) -> Result<Image> {
+    let request = DescribeImagesRequest {
+        image_ids: Some(vec![image_id.to_string()]),
+        ..Default::default()
+    };
+    ec2_client
+        .describe_images(request)
+        .await?
+        .images
+        .and_then(|images| images.into_iter().next())
+        .ok_or_else(|| AwsInstanceError::ImageNotFoundError {
+            image_id: image_id.into(),
+        })
+}
+
+/// Our own images
+pub async fn list_images(ec2_client: &Ec2Client) -> Result<()> {
+    let request = DescribeImagesRequest {
+        owners: Some(vec!["self".into()]),
+        ..Default::default()
+    };
+    let images = ec2_client
+        .describe_images(request)
+        .await?
+        .images
+        .unwrap_or_default();
+    if images.is_empty() {
+        println!("No images found");
+        return Ok(());
+    }
+
+    let mut image_info = images
+        .into_iter()
+        .map(AmiInfo::from_aws)
+        .collect::<Result<Vec<AmiInfo>>>()?;
+    image_info.sort_by_key(|image| std::cmp::Reverse(image.creation_date));
+    AmiInfo::print_header();
+    for image in image_info {
+        image.print();
+    }
+
+    Ok(())
+}
+
+/// Capture an AMI from an instance, tagged like the instance
+pub async fn create_image(
+    ec2_client: &Ec2Client,
+    name: &str,
+    image_name: &str,
+    no_reboot: bool,
+    wait: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let instance = util::get_instance_by_name(ec2_client, name)
+        .await?
+        .ok_or_else(|| AwsInstanceError::InstanceNotFoundError {
+            instance_name: name.into(),
+        })?;
+
+    // Tags starting with "aws:" are reserved, so they can't be copied
+    let tags: Vec<Tag> = instance
+        .tags
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|tag| {
+            !tag.key
+                .as_deref()
+                .map(|key| key.starts_with("aws:"))
+                .unwrap_or(true)
+        })
+        .collect();
+    let tag_specifications = if tags.is_empty() {
+        None
+    } else {
+        Some(
+            ["image", "snapshot"]
+                .iter()
+                .map(|resource_type| TagSpecification {
+                    resource_type: Some(resource_type.to_string()),
+                    tags: Some(tags.clone()),
+                })
+                .collect(),
+        )
+    };
+
+    let request = CreateImageRequest {
+        instance_id: instance.instance_id.unwrap(),
+        name: image_name.into(),
+        description: Some(format!("Created from {}", name)),
+        no_reboot: Some(no_reboot),
+        tag_specifications,
+        dry_run: Some(dry_run),
+        ..Default::default()
+    };
+    let image_id = match ec2_client.create_image(request).await {
+        Ok(result) => result.image_id.unwrap_or_default(),
+        Err(error) => {
+            return util::dry_run_ok(
+                error.into(),
+                &format!("create image {} from {}", image_name, name),
+            )
+        }
+    };
+    println!("Creating image {} from {}", image_id, name);
+
+    if wait {
+        let image = wait_for_image(ec2_client, &image_id).await?;
+        AmiInfo::print_header();
+        AmiInfo::from_aws(image)?.print();
+    }
+
+    Ok(())
+}
+
+async fn wait_for_image(ec2_client: &Ec2Client, image_id: &str) -> Result<Image> {
+    let started = Instant::now();
+    loop {
+        let image = describe_image(ec2_client, image_id).await?;
+        match image.state.as_deref() {
+            Some("available") => return Ok(image),
+            Some("pending") | None => (),
+            Some(state) => {
+                return Err(AwsInstanceError::ImageError {
+                    image_id: image_id.into(),
+                    message: format!(
+                        "Image is {}: {}",
+                        state,
+                        image
+                            .state_reason
+                            .and_then(|reason| reason.message)
+                            .unwrap_or_else(|| "no reason given".into())
+                    ),
+                })
+            }
+        }
+        if started.elapsed() > WAIT_TIMEOUT {
+            return Err(AwsInstanceError::ImageError {
+                image_id: image_id.into(),
+                message: format!("Not available after {} seconds", WAIT_TIMEOUT.as_secs()),
+            });
+        }
+        println!("Waiting for {}", image_id);
+        tokio::time::sleep(WAIT_INTERVAL).await;
+    }
+}
+
+/// Deregister an image and delete the snapshots behind it
+pub async fn delete_image(
+    ec2_client: &Ec2Client,
+    image_id: &str,
+    yes: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let image = describe_image(ec2_client, image_id).await?;
+    let snapshot_ids: Vec<String> = image
+        .block_device_mappings
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|mapping| mapping.ebs.and_then(|ebs| ebs.snapshot_id))
+        .collect();
+
+    if !yes && !dry_run {
+        let prompt = format!(
+            "This will deregister {} and delete {} snapshot(s). Type the image ID to confirm: ",
+            image_id,
+            snapshot_ids.len()
+        );
+        if !util::confirm(&prompt, image_id)? {
+            return Err(AwsInstanceError::ImageError {
+                image_id: image_id.into(),
+                message: "Not confirmed".into(),
+            });
+        }
+    }
+
+    let request = DeregisterImageRequest {
+        image_id: image_id.into(),
+        dry_run: Some(dry_run),
+    };
+    match ec2_client.deregister_image(request).await {
+        Ok(_) => println!("Deregistered {}", image_id),
+        Err(error) => util::dry_run_ok(error.into(), &format!("deregister {}", image_id))?,
+    }
+
+    for snapshot_id in snapshot_ids {
+        let request = DeleteSnapshotRequest {
+            snapshot_id: snapshot_id.clone(),
+            dry_run: Some(dry_run),
+        };
+        match ec2_client.delete_snapshot(request).await {
+            Ok(_) => println!("Deleted snapshot {}", snapshot_id),
+            Err(error) => {
+                util::dry_run_ok(error.into(), &format!("delete snapshot {}", snapshot_id))?
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Copy an image from this client's region into `destination_client`'s region
+pub async fn copy_image(
+    ec2_client: &Ec2Client,
+    source_region: &str,
+    destination_client: &Ec2Client,
+    destination_region: &str,
+    image_id: &str,
+    image_name: Option<String>,
+    dry_run: bool,
+) -> Result<()> {
+    let image = describe_image(ec2_client, image_id).await?;
+    let request = CopyImageRequest {
+        source_image_id: image_id.into(),
+        source_region: source_region.into(),
+        client_token: Some(util::client_token()),
+        name: image_name
+            .or(image.name)
+            .unwrap_or_else(|| image_id.to_string()),
+        description: image.description,
+        dry_run: Some(dry_run),
+        ..Default::default()
+    };
+    match destination_client.copy_image(request).await {
+        Ok(result) => println!(
+            "Copying {} to {} in {}",
+            image_id,
+            result.image_id.as_deref().unwrap_or("N/A"),
+            destination_region
+        ),
+        Err(error) => util::dry_run_ok(
+            error.into(),
+            &format!("copy {} to {}", image_id, destination_region),
+        )?,
+    }
+
+    Ok(())
+}
+
+/// Let other accounts launch an image, or stop them
+pub async fn share_image(
+    ec2_client: &Ec2Client,
+    image_id: &str,
+    accounts: &[String],
+    remove: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let permissions: Vec<LaunchPermission> = accounts
+        .iter()
+        .map(|account| LaunchPermission {
+            user_id: Some(account.clone()),
+            ..Default::default()
+        })
+        .collect();
+    let request = ModifyImageAttributeRequest {
+        image_id: image_id.into(),
+        launch_permission: Some(if remove {
+            LaunchPermissionModifications {
+                remove: Some(permissions),
+                ..Default::default()
+            }
+        } else {
+            LaunchPermissionModifications {
+                add: Some(permissions),
+                ..Default::default()
+            }
+        }),
+        dry_run: Some(dry_run),
+        ..Default::default()
+    };
+
+    let (action, done) = if remove {
+        ("unshare", "Unshared")
+    } else {
+        ("share", "Shared")
+    };
+    match ec2_client.modify_image_attribute(request).await {
+        Ok(_) => println!("{} {} with {}", done, image_id, accounts.join(", ")),
+        Err(error) => util::dry_run_ok(
+            error.into(),
+            &format!("{} {} with {}", action, image_id, accounts.join(", ")),
+        )?,
+    }
+
+    Ok(())
+}
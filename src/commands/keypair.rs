@@ -0,0 +1,177 @@
+use crate::profile::get_keys_dir;
+use crate::{util, AwsInstanceError, Result};
+use rusoto_ec2::{
+    CreateKeyPairRequest, DeleteKeyPairRequest, DescribeKeyPairsRequest, Ec2, Ec2Client,
+    ImportKeyPairRequest,
+};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where we keep the private key for a key pair
+pub fn key_path(name: &str) -> PathBuf {
+    let mut path = get_keys_dir();
+    path.push(format!("{}.pem", name));
+    path
+}
+
+/// Our private key for a key pair, if we have it
+pub fn find_key(name: &str) -> Option<PathBuf> {
+    Some(key_path(name)).filter(|path| path.is_file())
+}
+
+// Private keys must only be readable by us, or ssh refuses to use them
+fn write_private_key(path: &Path, key_material: &str) -> std::io::Result<()> {
+    let mut contents = key_material.to_string();
+    if !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    util::write_private_file(path, contents.as_bytes(), false)
+}
+
+/// Create a key pair in EC2 and save its private key, which AWS only hands out once
+pub async fn create_keypair(ec2_client: &Ec2Client, name: &str, dry_run: bool) -> Result<()> {
+    let path = key_path(name);
+    if path.exists() {
+        return Err(AwsInstanceError::Validation {
+            message: format!(
+                "{} already exists, delete it or use a different key pair name",
+                path.display()
+            ),
+        });
+    }
+
+    let request = CreateKeyPairRequest {
+        key_name: name.into(),
+        dry_run: Some(dry_run),
+        ..Default::default()
+    };
+    let key_pair = match ec2_client.create_key_pair(request).await {
+        Ok(key_pair) => key_pair,
+        Err(error) => {
+            return util::dry_run_ok(error.into(), &format!("create key pair {}", name));
+        }
+    };
+
+    write_private_key(&path, &key_pair.key_material.unwrap_or_default()).map_err(|error| {
+        AwsInstanceError::Service {
+            message: format!(
+                "Created key pair {} but couldn't save its private key to {}: {}",
+                name,
+                path.display(),
+                error
+            ),
+        }
+    })?;
+    println!(
+        "Created key pair {} ({}), private key saved to {}",
+        name,
+        key_pair.key_fingerprint.as_deref().unwrap_or("N/A"),
+        path.display()
+    );
+
+    Ok(())
+}
+
+/// Upload an existing public key as a key pair
+pub async fn import_keypair(
+    ec2_client: &Ec2Client,
+    name: &str,
+    public_key: &str,
+    dry_run: bool,
+) -> Result<()> {
+    let public_key_material =
+        fs::read(public_key).map_err(|error| AwsInstanceError::Validation {
+            message: format!("Error reading public key {}: {}", public_key, error),
+        })?;
+
+    let request = ImportKeyPairRequest {
+        key_name: name.into(),
+        // The API wants the key base64 encoded, and rusoto sends blobs as they are
+        public_key_material: base64::encode(public_key_material).into_bytes().into(),
+        dry_run: Some(dry_run),
+        ..Default::default()
+    };
+    match ec2_client.import_key_pair(request).await {
+        Ok(key_pair) => println!(
+            "Imported key pair {} ({})",
+            name,
+            key_pair.key_fingerprint.as_deref().unwrap_or("N/A")
+        ),
+        Err(error) => util::dry_run_ok(error.into(), &format!("import key pair {}", name))?,
+    }
+
+    Ok(())
+}
+
+pub async fn list_keypairs(ec2_client: &Ec2Client) -> Result<()> {
+    let mut key_pairs = ec2_client
+        .describe_key_pairs(DescribeKeyPairsRequest::default())
+        .await?
+        .key_pairs
+        .unwrap_or_default();
+    key_pairs.sort_by(|a, b| a.key_name.cmp(&b.key_name));
+
+    println!(
+        "{0: <25} {1: <25} {2: <50} Local key",
+        "Name", "Key pair ID", "Fingerprint"
+    );
+    for key_pair in key_pairs {
+        let name = key_pair.key_name.unwrap_or_default();
+        println!(
+            "{0: <25} {1: <25} {2: <50} {3}",
+            name,
+            key_pair.key_pair_id.as_deref().unwrap_or("N/A"),
+            key_pair.key_fingerprint.as_deref().unwrap_or("N/A"),
+            find_key(&name)
+                .map(|path| path.display().to_string())
+                .unwrap_or_else(|| "N/A".into()),
+        );
+    }
+
+    Ok(())
+}
+
+/// Delete a key pair from EC2. The local private key is kept, since instances launched with
+/// the key pair still accept it.
+pub async fn delete_keypair(
+    ec2_client: &Ec2Client,
+    name: &str,
+    yes: bool,
+    dry_run: bool,
+) -> Result<()> {
+    if !yes
+        && !dry_run
+        && !util::confirm(
+            &format!(
+                "This will delete key pair {}. Type the key pair name to confirm: ",
+                name
+            ),
+            name,
+        )?
+    {
+        return Err(AwsInstanceError::Validation {
+            message: format!("Confirmation did not match key pair name {}", name),
+        });
+    }
+
+    let request = DeleteKeyPairRequest {
+        key_name: Some(name.into()),
+        dry_run: Some(dry_run),
+        ..Default::default()
+    };
+    match ec2_client.delete_key_pair(request).await {
+        Ok(_) => {
+            println!("Deleted key pair {}", name);
+            if let Some(path) = find_key(name) {
+                println!(
+                    "Kept {}, which instances launched with {} still accept",
+                    path.display(),
+                    name
+                );
+            }
+        }
+        Err(error) => util::dry_run_ok(error.into(), &format!("delete key pair {}", name))?,
+    }
+
+    Ok(())
+}
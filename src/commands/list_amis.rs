@@ -1,19 +1,31 @@
+use crate::ami_cache::AmiCache;
+use crate::cmdline::OsNames;
 use crate::Result;
 use chrono::{DateTime, SecondsFormat, Utc};
+use clap::ValueEnum;
+use lazy_static::lazy_static;
 use regex::Regex;
-use rusoto_ec2::{DescribeImagesRequest, Ec2, Ec2Client, Filter, Image};
-use std::collections::HashMap;
+use rusoto_ec2::{DescribeImagesRequest, Ec2Client, Filter, Image};
+use std::collections::{BTreeSet, HashMap};
 
-struct AmiInfo {
-    ami_id: Option<String>,
-    state: Option<String>,
-    creation_date: Option<DateTime<Utc>>,
-    name: Option<String>,
-    description: Option<String>,
+lazy_static! {
+    // Date stamps (and any version or build numbers around them) in image names
+    static ref DATE_STAMP: Regex = Regex::new(r"[\d.]*\d{8}[\d.\-]*").unwrap();
+}
+
+pub struct AmiInfo {
+    pub ami_id: Option<String>,
+    pub state: Option<String>,
+    pub creation_date: Option<DateTime<Utc>>,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub architecture: Option<String>,
+    pub owner: Option<String>,
+    pub root_device_type: Option<String>,
 }
 
 impl AmiInfo {
-    fn from_aws(image: Image) -> Result<Self> {
+    pub fn from_aws(image: Image) -> Result<Self> {
         Ok(AmiInfo {
             ami_id: image.image_id,
             state: image.state,
@@ -23,12 +35,30 @@ impl AmiInfo {
                 .map_or(Ok(None), |d| d.map(Some))?,
             name: image.name,
             description: image.description,
+            architecture: image.architecture,
+            owner: image.image_owner_alias.or(image.owner_id),
+            root_device_type: image.root_device_type,
         })
     }
 
-    fn print(&self) {
+    pub fn print_header() {
         println!(
-            "{0: <15} {1: <15} {2: <25} {3: <50.48} {4: <25}",
+            "{0: <22} {1: <10} {2: <25} {3: <8} {4: <13} {5: <6} {6: <50.48} {7: <25}",
+            "AMI ID", "State", "Creation Date", "Arch", "Owner", "Root", "Name", "Description"
+        );
+    }
+
+    /// The image's name with any date stamp taken out, so successive releases of the same
+    /// image share a family
+    pub fn family(&self) -> Option<String> {
+        self.name
+            .as_ref()
+            .map(|name| DATE_STAMP.replace_all(name, "*").to_string())
+    }
+
+    pub fn print(&self) {
+        println!(
+            "{0: <22} {1: <10} {2: <25} {3: <8} {4: <13} {5: <6} {6: <50.48} {7: <25}",
             print_option(&self.ami_id),
             print_option(&self.state),
             print_option(
@@ -36,6 +66,9 @@ impl AmiInfo {
                     .creation_date
                     .map(|d| d.to_rfc3339_opts(SecondsFormat::Millis, true))
             ),
+            print_option(&self.architecture),
+            print_option(&self.owner),
+            print_option(&self.root_device_type),
             print_option(&self.name),
             print_option(&self.description)
         );
@@ -49,12 +82,32 @@ fn print_option(option: &Option<String>) -> String {
     }
 }
 
+/// The owners to search when none are given: our own images, and those of the OS vendors
+pub fn default_owners() -> Vec<String> {
+    let mut owners: BTreeSet<&str> = OsNames::value_variants()
+        .iter()
+        .map(|os_name| os_name.ami_source().0)
+        .collect();
+    owners.insert("self");
+    owners.into_iter().map(|owner| owner.to_string()).collect()
+}
+
 pub async fn list_amis(
     ec2_client: &Ec2Client,
+    ami_cache: &AmiCache,
+    owners: &[String],
     filter_values: &HashMap<String, Vec<String>>,
     search_string: Option<String>,
+    latest: bool,
 ) -> Result<()> {
-    let mut request = DescribeImagesRequest::default();
+    let mut request = DescribeImagesRequest {
+        owners: if owners.is_empty() {
+            None
+        } else {
+            Some(owners.to_vec())
+        },
+        ..Default::default()
+    };
     if !filter_values.is_empty() {
         let mut filters = vec![];
         for (key, values) in filter_values.iter() {
@@ -72,8 +125,8 @@ pub async fn list_amis(
         None => None,
         Some(ref search) => Some(Regex::new(search)?),
     };
-    match ec2_client.describe_images(request).await?.images {
-        Some(images) => {
+    match ami_cache.describe_images(ec2_client, request).await? {
+        images if !images.is_empty() => {
             for image in images {
                 match search_regex {
                     None => {
@@ -88,19 +141,51 @@ pub async fn list_amis(
                     }
                 }
             }
-            image_info.sort_by(|a, b| b.creation_date.cmp(&a.creation_date));
-            println!(
-                "{0: <15} {1: <15} {2: <25} {3: <50.48} {4: <25}",
-                "AMI ID", "State", "Creation Date", "Name", "Description"
-            );
+            image_info.sort_by_key(|image| std::cmp::Reverse(image.creation_date));
+            if latest {
+                // Newest first, so the first image seen in each family is the one to keep
+                let mut families = BTreeSet::new();
+                image_info.retain(|image| families.insert((image.owner.clone(), image.family())));
+            }
+            AmiInfo::print_header();
             for image in image_info {
                 image.print();
             }
         }
-        None => {
+        _ => {
             println!("No images found");
         }
     };
 
     Ok(())
 }
+
+/// Find the newest official AMI for an OS
+pub async fn find_latest_ami(
+    ec2_client: &Ec2Client,
+    ami_cache: &AmiCache,
+    os_name: &OsNames,
+    architecture: &str,
+) -> Result<Option<String>> {
+    let (owner, name_pattern) = os_name.ami_source();
+    let filter = |name: &str, value: &str| Filter {
+        name: Some(name.into()),
+        values: Some(vec![value.into()]),
+    };
+    let request = DescribeImagesRequest {
+        owners: Some(vec![owner.into()]),
+        filters: Some(vec![
+            filter("name", name_pattern),
+            filter("architecture", architecture),
+            filter("state", "available"),
+        ]),
+        ..Default::default()
+    };
+
+    let images = ami_cache.describe_images(ec2_client, request).await?;
+    // Creation dates are ISO 8601, so they sort as strings
+    Ok(images
+        .into_iter()
+        .max_by(|a, b| a.creation_date.cmp(&b.creation_date))
+        .and_then(|image| image.image_id))
+}
@@ -0,0 +1,68 @@
+use crate::Result;
+use rusoto_ec2::{
+    DescribeLaunchTemplateVersionsRequest, DescribeLaunchTemplatesRequest, Ec2, Ec2Client,
+};
+
+pub async fn list_launch_templates(ec2_client: &Ec2Client, name: &Option<String>) -> Result<()> {
+    let request = DescribeLaunchTemplatesRequest {
+        launch_template_names: name.clone().map(|name| vec![name]),
+        ..Default::default()
+    };
+    let launch_templates = ec2_client
+        .describe_launch_templates(request)
+        .await?
+        .launch_templates
+        .unwrap_or_default();
+    if launch_templates.is_empty() {
+        println!("No launch templates found");
+        return Ok(());
+    }
+
+    println!(
+        "{0: <22} {1: <25} {2: <8} {3: <15} {4: <22} {5: <20} Description",
+        "ID", "Name", "Version", "Instance Type", "AMI ID", "Key Pair"
+    );
+    for launch_template in launch_templates {
+        let request = DescribeLaunchTemplateVersionsRequest {
+            launch_template_id: launch_template.launch_template_id.clone(),
+            ..Default::default()
+        };
+        let mut versions = ec2_client
+            .describe_launch_template_versions(request)
+            .await?
+            .launch_template_versions
+            .unwrap_or_default();
+        versions.sort_by_key(|version| version.version_number);
+
+        for version in versions {
+            let data = version.launch_template_data.unwrap_or_default();
+            let version_number = version
+                .version_number
+                .map(|number| number.to_string())
+                .unwrap_or_else(|| "N/A".into());
+            println!(
+                "{0: <22} {1: <25} {2: <8} {3: <15} {4: <22} {5: <20} {6}",
+                launch_template
+                    .launch_template_id
+                    .as_deref()
+                    .unwrap_or("N/A"),
+                launch_template
+                    .launch_template_name
+                    .as_deref()
+                    .unwrap_or("N/A"),
+                if version.default_version == Some(true) {
+                    format!("{}*", version_number)
+                } else {
+                    version_number
+                },
+                data.instance_type.as_deref().unwrap_or("N/A"),
+                data.image_id.as_deref().unwrap_or("N/A"),
+                data.key_name.as_deref().unwrap_or("N/A"),
+                version.version_description.as_deref().unwrap_or("")
+            );
+        }
+    }
+    println!("\n* default version");
+
+    Ok(())
+}
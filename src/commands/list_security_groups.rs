@@ -1,7 +1,15 @@
+use crate::commands::security_group::{format_icmp, group_usage, is_icmp, PortRange};
 use crate::Result;
-use rusoto_ec2::{DescribeSecurityGroupsRequest, Ec2, Ec2Client, Filter};
+use rusoto_ec2::{
+    DescribeSecurityGroupsRequest, Ec2, Ec2Client, Filter, IpPermission, SecurityGroup,
+};
+use std::collections::HashMap;
 
-pub async fn list_security_groups(ec2_client: &Ec2Client, name: &Option<String>) -> Result<()> {
+pub async fn list_security_groups(
+    ec2_client: &Ec2Client,
+    name: &Option<String>,
+    usage: bool,
+) -> Result<()> {
     let mut request = DescribeSecurityGroupsRequest::default();
     if name.is_some() {
         request.filters = Some(vec![Filter {
@@ -10,86 +18,234 @@ pub async fn list_security_groups(ec2_client: &Ec2Client, name: &Option<String>)
         }]);
     }
     let result = ec2_client.describe_security_groups(request).await?;
-    println!(
-        "{0: <10} {1: <25} {2: <35}",
-        "Name", "Group ID", "Description"
-    );
-    if name.is_some() {
-        if let Some(security_groups) = result.security_groups {
-            for group in security_groups {
-                println!(
-                    "{0: <10} {1: <25} {2: <35}",
-                    group.group_name.unwrap_or_else(|| "N/A".to_string()),
-                    group.group_id.unwrap_or_else(|| "N/A".to_string()),
-                    group.description.unwrap_or_else(|| "N/A".to_string()),
-                );
-                println!("  ingress:");
-                for ingress in group.ip_permissions.unwrap_or_default() {
-                    let from_port_string = ingress
-                        .from_port
-                        .map(|p| p.to_string())
-                        .unwrap_or_else(|| "N/A".to_string());
-                    let to_port_string = ingress
-                        .to_port
-                        .map(|p| p.to_string())
-                        .unwrap_or_else(|| "N/A".to_string());
-                    let port_string = if from_port_string == to_port_string {
-                        from_port_string
-                    } else {
-                        format!("{}:{}", from_port_string, to_port_string)
-                    };
-                    println!(
-                        "    {0: <10} {1: <15}",
-                        port_string,
-                        ingress
-                            .ip_ranges
-                            .map(|v| v
-                                .iter()
-                                .map(|ip| ip.cidr_ip.clone().unwrap_or_else(|| "N/A".to_string()))
-                                .collect::<Vec<String>>()
-                                .join(", "))
-                            .unwrap_or_else(|| "N/A".to_string()),
-                    );
-                }
-                println!("  egress:");
-                for egress in group.ip_permissions_egress.unwrap_or_default() {
-                    let from_port_string = egress
-                        .from_port
-                        .map(|p| p.to_string())
-                        .unwrap_or_else(|| "N/A".to_string());
-                    let to_port_string = egress
-                        .to_port
-                        .map(|p| p.to_string())
-                        .unwrap_or_else(|| "N/A".to_string());
-                    let port_string = if from_port_string == to_port_string {
-                        from_port_string
-                    } else {
-                        format!("{}:{}", from_port_string, to_port_string)
-                    };
-                    println!(
-                        "    {0: <10} {1: <15}",
-                        port_string,
-                        egress
-                            .ip_ranges
-                            .map(|v| v
-                                .iter()
-                                .map(|ip| ip.cidr_ip.clone().unwrap_or_else(|| "N/A".to_string()))
-                                .collect::<Vec<String>>()
-                                .join(", "))
-                            .unwrap_or_else(|| "N/A".to_string()),
-                    );
-                }
-            }
+    let group_names = match (name, &result.security_groups) {
+        (Some(_), Some(security_groups)) => {
+            referenced_group_names(ec2_client, security_groups).await
         }
-    } else if let Some(security_groups) = result.security_groups {
-        for group in security_groups {
-            println!(
+        _ => HashMap::new(),
+    };
+    let group_usage = if usage {
+        Some(group_usage(ec2_client).await?)
+    } else {
+        None
+    };
+
+    if usage {
+        println!(
+            "{0: <10} {1: <25} {2: <35} Used by",
+            "Name", "Group ID", "Description"
+        );
+    } else {
+        println!(
+            "{0: <10} {1: <25} {2: <35}",
+            "Name", "Group ID", "Description"
+        );
+    }
+    for group in result.security_groups.unwrap_or_default() {
+        let group_id = group.group_id.clone().unwrap_or_else(|| "N/A".to_string());
+        let group_name = group
+            .group_name
+            .clone()
+            .unwrap_or_else(|| "N/A".to_string());
+        let description = group
+            .description
+            .clone()
+            .unwrap_or_else(|| "N/A".to_string());
+        match group_usage {
+            Some(ref group_usage) => println!(
+                "{0: <10} {1: <25} {2: <35} {3}",
+                group_name,
+                group_id,
+                description,
+                group_usage
+                    .get(&group_id)
+                    .map(|users| users.join(", "))
+                    .unwrap_or_else(|| "None".to_string()),
+            ),
+            None => println!(
                 "{0: <10} {1: <25} {2: <35}",
-                group.group_name.unwrap_or_else(|| "N/A".to_string()),
-                group.group_id.unwrap_or_else(|| "N/A".to_string()),
-                group.description.unwrap_or_else(|| "N/A".to_string()),
-            );
+                group_name, group_id, description,
+            ),
+        }
+        if name.is_some() {
+            print_rules("ingress", &group.ip_permissions, &group_names);
+            print_rules("egress", &group.ip_permissions_egress, &group_names);
         }
     }
     Ok(())
 }
+
+/// Names for the groups that rules refer to, by ID. Rules only name the groups they refer
+/// to in the default VPC, so we have to look the rest up.
+async fn referenced_group_names(
+    ec2_client: &Ec2Client,
+    security_groups: &[SecurityGroup],
+) -> HashMap<String, String> {
+    let mut group_names: HashMap<String, String> = security_groups
+        .iter()
+        .filter_map(|group| Some((group.group_id.clone()?, group.group_name.clone()?)))
+        .collect();
+    let mut missing: Vec<String> = security_groups
+        .iter()
+        .flat_map(|group| {
+            group
+                .ip_permissions
+                .iter()
+                .chain(group.ip_permissions_egress.iter())
+        })
+        .flatten()
+        .flat_map(|permission| permission.user_id_group_pairs.iter().flatten())
+        .filter_map(|pair| pair.group_id.clone())
+        .filter(|group_id| !group_names.contains_key(group_id))
+        .collect();
+    missing.sort();
+    missing.dedup();
+    if missing.is_empty() {
+        return group_names;
+    }
+
+    // Groups in other accounts can't be described, so those just show their ID
+    let request = DescribeSecurityGroupsRequest {
+        group_ids: Some(missing),
+        ..Default::default()
+    };
+    if let Ok(result) = ec2_client.describe_security_groups(request).await {
+        for group in result.security_groups.unwrap_or_default() {
+            if let (Some(group_id), Some(group_name)) = (group.group_id, group.group_name) {
+                group_names.insert(group_id, group_name);
+            }
+        }
+    }
+    group_names
+}
+
+fn print_rules(
+    direction: &str,
+    permissions: &Option<Vec<IpPermission>>,
+    group_names: &HashMap<String, String>,
+) {
+    println!("  {}:", direction);
+    let permissions = permissions.as_deref().unwrap_or_default();
+    if permissions.is_empty() {
+        println!("    None");
+        return;
+    }
+
+    println!(
+        "    {0: <10} {1: <12} {2: <45} Description",
+        "Protocol",
+        "Ports",
+        if direction == "egress" {
+            "Destination"
+        } else {
+            "Source"
+        }
+    );
+    for permission in permissions {
+        for line in format_rule(permission, group_names) {
+            println!("    {}", line);
+        }
+    }
+}
+
+/// One line per source in a rule: its protocol, ports, where the traffic comes from (or goes
+/// to) and the source's description
+pub fn format_rule(
+    permission: &IpPermission,
+    group_names: &HashMap<String, String>,
+) -> Vec<String> {
+    let protocol = format_protocol(permission.ip_protocol.as_deref());
+    let ports = if protocol == "all" {
+        "all".to_string()
+    } else if is_icmp(&protocol) {
+        format_icmp(PortRange {
+            from: permission.from_port.unwrap_or(-1),
+            to: permission.to_port.unwrap_or(-1),
+        })
+    } else {
+        format_port_range(permission.from_port, permission.to_port)
+    };
+
+    let mut sources: Vec<(String, Option<String>)> = vec![];
+    for range in permission.ip_ranges.iter().flatten() {
+        sources.push((
+            range.cidr_ip.clone().unwrap_or_else(|| "N/A".to_string()),
+            range.description.clone(),
+        ));
+    }
+    for range in permission.ipv_6_ranges.iter().flatten() {
+        sources.push((
+            range
+                .cidr_ipv_6
+                .clone()
+                .unwrap_or_else(|| "N/A".to_string()),
+            range.description.clone(),
+        ));
+    }
+    for prefix_list in permission.prefix_list_ids.iter().flatten() {
+        sources.push((
+            prefix_list
+                .prefix_list_id
+                .clone()
+                .unwrap_or_else(|| "N/A".to_string()),
+            prefix_list.description.clone(),
+        ));
+    }
+    for pair in permission.user_id_group_pairs.iter().flatten() {
+        let group_id = pair.group_id.clone().unwrap_or_else(|| "N/A".to_string());
+        let group_name = pair
+            .group_name
+            .clone()
+            .or_else(|| group_names.get(&group_id).cloned());
+        let mut source = match pair.user_id {
+            // Only mention the account when the group isn't one of ours
+            Some(ref user_id) if group_name.is_none() => format!("{}/{}", user_id, group_id),
+            _ => group_id,
+        };
+        if let Some(group_name) = group_name {
+            source = format!("{} ({})", source, group_name);
+        }
+        sources.push((source, pair.description.clone()));
+    }
+
+    sources
+        .into_iter()
+        .map(|(source, description)| {
+            format!(
+                "{0: <10} {1: <12} {2: <45} {3}",
+                protocol,
+                ports,
+                source,
+                description.unwrap_or_default()
+            )
+            .trim_end()
+            .to_string()
+        })
+        .collect()
+}
+
+/// "-1" means every protocol
+pub fn format_protocol(protocol: Option<&str>) -> String {
+    match protocol {
+        Some("-1") => "all".to_string(),
+        Some(protocol) => protocol.to_string(),
+        None => "N/A".to_string(),
+    }
+}
+
+/// Format a rule's ports as `22`, or `8000-8100` for a range. Ports of -1 (as in ICMP rules
+/// covering every type) mean all of them.
+pub fn format_port_range(from_port: Option<i64>, to_port: Option<i64>) -> String {
+    let format_port = |port: Option<i64>| match port {
+        Some(-1) => "all".to_string(),
+        Some(port) => port.to_string(),
+        None => "N/A".to_string(),
+    };
+    let from_port_string = format_port(from_port);
+    let to_port_string = format_port(to_port);
+    if from_port_string == to_port_string {
+        from_port_string
+    } else {
+        format!("{}-{}", from_port_string, to_port_string)
+    }
+}
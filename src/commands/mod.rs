@@ -1,8 +1,18 @@
+pub mod config;
 pub mod create;
 pub mod destroy;
+pub mod environment;
+pub mod image;
+pub mod keypair;
 pub mod list;
 pub mod list_amis;
+pub mod list_launch_templates;
 pub mod list_security_groups;
+pub mod protect;
+pub mod security_group;
+pub mod security_group_file;
+pub mod snapshot;
 pub mod ssh;
 pub mod start;
 pub mod stop;
+pub mod volume;
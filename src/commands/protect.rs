@@ -0,0 +1,62 @@
+use crate::{util, AwsInstanceError, Result};
+use rusoto_ec2::{
+    AttributeBooleanValue, DescribeInstanceAttributeRequest, Ec2, Ec2Client,
+    ModifyInstanceAttributeRequest,
+};
+
+pub async fn is_termination_protected(ec2_client: &Ec2Client, instance_id: &str) -> Result<bool> {
+    let request = DescribeInstanceAttributeRequest {
+        attribute: "disableApiTermination".into(),
+        instance_id: instance_id.into(),
+        ..Default::default()
+    };
+    let result = ec2_client.describe_instance_attribute(request).await?;
+
+    Ok(result
+        .disable_api_termination
+        .and_then(|attribute| attribute.value)
+        .unwrap_or(false))
+}
+
+pub async fn protect(
+    ec2_client: &Ec2Client,
+    name: &str,
+    enable: bool,
+    dry_run: bool,
+) -> Result<()> {
+    match util::get_instance_by_name(ec2_client, name).await? {
+        Some(instance) => {
+            let instance_id = instance.instance_id.unwrap();
+            let request = ModifyInstanceAttributeRequest {
+                instance_id: instance_id.clone(),
+                disable_api_termination: Some(AttributeBooleanValue {
+                    value: Some(enable),
+                }),
+                dry_run: Some(dry_run),
+                ..Default::default()
+            };
+            if let Err(error) = ec2_client.modify_instance_attribute(request).await {
+                return util::dry_run_ok(
+                    error.into(),
+                    &format!(
+                        "{} termination protection on {}",
+                        if enable { "enable" } else { "disable" },
+                        name
+                    ),
+                );
+            }
+
+            println!(
+                "{}: termination protection {}",
+                instance_id,
+                if enable { "enabled" } else { "disabled" }
+            );
+        }
+        None => {
+            return Err(AwsInstanceError::InstanceNotFoundError {
+                instance_name: name.into(),
+            })
+        }
+    }
+    Ok(())
+}
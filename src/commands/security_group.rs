@@ -0,0 +1,908 @@
+use crate::commands::list_security_groups::format_port_range;
+use crate::{util, AwsInstanceError, Result};
+use chrono::Utc;
+use rusoto_ec2::{
+    AuthorizeSecurityGroupEgressRequest, AuthorizeSecurityGroupIngressRequest,
+    CreateSecurityGroupRequest, DeleteSecurityGroupRequest, DescribeNetworkInterfacesRequest,
+    DescribeSecurityGroupsRequest, DescribeSubnetsRequest, DescribeVpcsRequest, Ec2, Ec2Client,
+    Filter, Instance, IpPermission, IpRange, Ipv6Range, PrefixListId,
+    RevokeSecurityGroupEgressRequest, RevokeSecurityGroupIngressRequest, SecurityGroup,
+    UpdateSecurityGroupRuleDescriptionsEgressRequest,
+    UpdateSecurityGroupRuleDescriptionsIngressRequest, UserIdGroupPair,
+};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::net::IpAddr;
+
+// Rules added by `allow-me` have a description starting with this, then the user and time
+const ALLOW_ME_PREFIX: &str = "aws-instance allow-me";
+
+/// A port, or range of ports, written as `22`, `8000-8100` or `all`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PortRange {
+    pub from: i64,
+    pub to: i64,
+}
+
+impl std::fmt::Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self.from, self.to) {
+            (-1, -1) => write!(f, "all"),
+            (from, to) if from == to => write!(f, "{}", from),
+            (from, to) => write!(f, "{}-{}", from, to),
+        }
+    }
+}
+
+impl std::str::FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let error = || format!("Invalid port '{}', expected PORT, FROM-TO or all", s);
+        let port = |p: &str| p.trim().parse::<u16>().map(i64::from).map_err(|_| error());
+        match s.split_once('-') {
+            _ if s == "all" => Ok(PortRange { from: -1, to: -1 }),
+            Some((from, to)) => match (port(from)?, port(to)?) {
+                (from, to) if from > to => Err(format!(
+                    "Invalid port range '{}', the first port must not be higher than the last",
+                    s
+                )),
+                (from, to) => Ok(PortRange { from, to }),
+            },
+            None => Ok(PortRange {
+                from: port(s)?,
+                to: port(s)?,
+            }),
+        }
+    }
+}
+
+pub fn is_icmp(protocol: &str) -> bool {
+    matches!(protocol, "icmp" | "icmpv6" | "1" | "58")
+}
+
+/// Write an ICMP type and code as `TYPE-CODE`, `TYPE` (for any code) or `all`
+pub fn format_icmp(ports: PortRange) -> String {
+    match (ports.from, ports.to) {
+        (-1, _) => "all".into(),
+        (icmp_type, -1) => icmp_type.to_string(),
+        (icmp_type, code) => format!("{}-{}", icmp_type, code),
+    }
+}
+
+/// Read an ICMP type and code, written as for `format_icmp`
+pub fn parse_icmp(s: &str) -> std::result::Result<PortRange, String> {
+    let error = || format!("Invalid ICMP type '{}', expected TYPE, TYPE-CODE or all", s);
+    let number = |n: &str| n.trim().parse::<u8>().map(i64::from).map_err(|_| error());
+    match s.split_once('-') {
+        _ if s == "all" => Ok(PortRange { from: -1, to: -1 }),
+        Some((icmp_type, code)) => Ok(PortRange {
+            from: number(icmp_type)?,
+            to: number(code)?,
+        }),
+        None => Ok(PortRange {
+            from: number(s)?,
+            to: -1,
+        }),
+    }
+}
+
+/// Where traffic allowed by a rule comes from (or, for egress, goes to)
+#[derive(Clone, Debug, PartialEq)]
+pub enum RuleSource {
+    Cidr(String),
+    Group(String),
+    PrefixList(String),
+}
+
+impl std::fmt::Display for RuleSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RuleSource::Cidr(cidr) => write!(f, "{}", cidr),
+            RuleSource::Group(group_id) => write!(f, "{}", group_id),
+            RuleSource::PrefixList(prefix_list_id) => write!(f, "{}", prefix_list_id),
+        }
+    }
+}
+
+impl RuleSource {
+    /// A single address, as a /32 (or /128 for IPv6)
+    pub fn from_ip(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => RuleSource::Cidr(format!("{}/32", ip)),
+            IpAddr::V6(ip) => RuleSource::Cidr(format!("{}/128", ip)),
+        }
+    }
+}
+
+/// One rule to add to, or remove from, a security group
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub protocol: String,
+    pub ports: PortRange,
+    pub source: RuleSource,
+    pub description: Option<String>,
+}
+
+impl Rule {
+    fn ip_permission(&self) -> IpPermission {
+        // "all traffic" rules can't have ports
+        let (protocol, ports) = if self.protocol == "all" || self.protocol == "-1" {
+            ("-1".to_string(), None)
+        } else {
+            (self.protocol.clone(), Some(self.ports))
+        };
+        let mut permission = IpPermission {
+            ip_protocol: Some(protocol),
+            from_port: ports.map(|ports| ports.from),
+            to_port: ports.map(|ports| ports.to),
+            ..Default::default()
+        };
+        match self.source {
+            RuleSource::Cidr(ref cidr) if cidr.contains(':') => {
+                permission.ipv_6_ranges = Some(vec![Ipv6Range {
+                    cidr_ipv_6: Some(cidr.clone()),
+                    description: self.description.clone(),
+                }]);
+            }
+            RuleSource::Cidr(ref cidr) => {
+                permission.ip_ranges = Some(vec![IpRange {
+                    cidr_ip: Some(cidr.clone()),
+                    description: self.description.clone(),
+                }]);
+            }
+            RuleSource::Group(ref group_id) => {
+                permission.user_id_group_pairs = Some(vec![UserIdGroupPair {
+                    group_id: Some(group_id.clone()),
+                    description: self.description.clone(),
+                    ..Default::default()
+                }]);
+            }
+            RuleSource::PrefixList(ref prefix_list_id) => {
+                permission.prefix_list_ids = Some(vec![PrefixListId {
+                    prefix_list_id: Some(prefix_list_id.clone()),
+                    description: self.description.clone(),
+                }]);
+            }
+        }
+        permission
+    }
+
+    /// Split one of AWS's rules, which can have many sources, into one rule per source
+    pub fn from_permission(permission: &IpPermission) -> Vec<Rule> {
+        let protocol = match permission.ip_protocol.as_deref() {
+            Some("-1") | None => "all".to_string(),
+            Some(protocol) => protocol.to_lowercase(),
+        };
+        let ports = PortRange {
+            from: permission.from_port.unwrap_or(-1),
+            to: permission.to_port.unwrap_or(-1),
+        };
+        let rule = |source: RuleSource, description: &Option<String>| Rule {
+            protocol: protocol.clone(),
+            ports,
+            source,
+            description: description.clone(),
+        };
+
+        let mut rules = vec![];
+        for range in permission.ip_ranges.iter().flatten() {
+            if let Some(ref cidr) = range.cidr_ip {
+                rules.push(rule(RuleSource::Cidr(cidr.clone()), &range.description));
+            }
+        }
+        for range in permission.ipv_6_ranges.iter().flatten() {
+            if let Some(ref cidr) = range.cidr_ipv_6 {
+                rules.push(rule(RuleSource::Cidr(cidr.clone()), &range.description));
+            }
+        }
+        for prefix_list in permission.prefix_list_ids.iter().flatten() {
+            if let Some(ref prefix_list_id) = prefix_list.prefix_list_id {
+                rules.push(rule(
+                    RuleSource::PrefixList(prefix_list_id.clone()),
+                    &prefix_list.description,
+                ));
+            }
+        }
+        for pair in permission.user_id_group_pairs.iter().flatten() {
+            if let Some(ref group_id) = pair.group_id {
+                rules.push(rule(RuleSource::Group(group_id.clone()), &pair.description));
+            }
+        }
+        rules
+    }
+
+    /// Whether two rules let the same traffic through, whatever their descriptions
+    pub fn same_traffic(&self, other: &Rule) -> bool {
+        let is_all = |rule: &Rule| rule.protocol == "all" || rule.protocol == "-1";
+        self.source == other.source
+            && ((is_all(self) && is_all(other))
+                || (self.protocol.eq_ignore_ascii_case(&other.protocol)
+                    && self.ports == other.ports))
+    }
+}
+
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.protocol == "all" || self.protocol == "-1" {
+            write!(f, "all traffic {}", self.source)
+        } else if is_icmp(&self.protocol) {
+            write!(
+                f,
+                "{} {} {}",
+                self.protocol,
+                format_icmp(self.ports),
+                self.source
+            )
+        } else {
+            write!(
+                f,
+                "{} {} {}",
+                self.protocol,
+                format_port_range(Some(self.ports.from), Some(self.ports.to)),
+                self.source
+            )
+        }
+    }
+}
+
+/// Find a security group by ID, or by name
+pub async fn get_security_group(ec2_client: &Ec2Client, group: &str) -> Result<SecurityGroup> {
+    if !group.starts_with("sg-") {
+        let mut groups = groups_named(ec2_client, &[group], None).await?;
+        return match groups.len() {
+            1 => Ok(groups.remove(0)),
+            matches => Err(AwsInstanceError::SecurityGroupNameError {
+                group_name: group.into(),
+                vpc_id: None,
+                matches,
+            }),
+        };
+    }
+
+    let request = DescribeSecurityGroupsRequest {
+        group_ids: Some(vec![group.into()]),
+        ..Default::default()
+    };
+    ec2_client
+        .describe_security_groups(request)
+        .await?
+        .security_groups
+        .and_then(|groups| groups.into_iter().next())
+        .ok_or_else(|| AwsInstanceError::SecurityGroupError {
+            group: group.into(),
+            message: "Not found".into(),
+        })
+}
+
+async fn groups_named(
+    ec2_client: &Ec2Client,
+    names: &[&str],
+    vpc_id: Option<&str>,
+) -> Result<Vec<SecurityGroup>> {
+    let mut filters = vec![Filter {
+        name: Some("group-name".into()),
+        values: Some(names.iter().map(|name| name.to_string()).collect()),
+    }];
+    if let Some(vpc_id) = vpc_id {
+        filters.push(Filter {
+            name: Some("vpc-id".into()),
+            values: Some(vec![vpc_id.into()]),
+        });
+    }
+    let request = DescribeSecurityGroupsRequest {
+        filters: Some(filters),
+        ..Default::default()
+    };
+    Ok(ec2_client
+        .describe_security_groups(request)
+        .await?
+        .security_groups
+        .unwrap_or_default())
+}
+
+/// The VPC an instance will be launched into: the subnet's, or the region's default VPC
+async fn target_vpc(ec2_client: &Ec2Client, subnet_id: Option<&str>) -> Result<Option<String>> {
+    if let Some(subnet_id) = subnet_id {
+        let request = DescribeSubnetsRequest {
+            subnet_ids: Some(vec![subnet_id.into()]),
+            ..Default::default()
+        };
+        return Ok(ec2_client
+            .describe_subnets(request)
+            .await?
+            .subnets
+            .and_then(|subnets| subnets.into_iter().next())
+            .and_then(|subnet| subnet.vpc_id));
+    }
+
+    let request = DescribeVpcsRequest {
+        filters: Some(vec![Filter {
+            name: Some("is-default".into()),
+            values: Some(vec!["true".into()]),
+        }]),
+        ..Default::default()
+    };
+    Ok(ec2_client
+        .describe_vpcs(request)
+        .await?
+        .vpcs
+        .and_then(|vpcs| vpcs.into_iter().next())
+        .and_then(|vpc| vpc.vpc_id))
+}
+
+/// Turn a list of security groups, given as IDs or names, into IDs. Names are looked up in
+/// the VPC the instance will be launched into, since each VPC can have a group of that name.
+pub async fn resolve_security_group_ids(
+    ec2_client: &Ec2Client,
+    groups: &[String],
+    subnet_id: Option<&str>,
+) -> Result<Vec<String>> {
+    let names: Vec<&str> = groups
+        .iter()
+        .map(String::as_str)
+        .filter(|group| !group.starts_with("sg-"))
+        .collect();
+    if names.is_empty() {
+        return Ok(groups.to_vec());
+    }
+
+    let vpc_id = target_vpc(ec2_client, subnet_id).await?;
+    let vpc_id = vpc_id.as_deref();
+    let found = groups_named(ec2_client, &names, vpc_id).await?;
+    groups
+        .iter()
+        .map(|group| {
+            if group.starts_with("sg-") {
+                return Ok(group.clone());
+            }
+            let matching: Vec<&SecurityGroup> = found
+                .iter()
+                .filter(|found| found.group_name.as_ref() == Some(group))
+                .collect();
+            match matching.as_slice() {
+                [security_group] => Ok(security_group.group_id.clone().unwrap_or_default()),
+                _ => Err(AwsInstanceError::SecurityGroupNameError {
+                    group_name: group.clone(),
+                    vpc_id: vpc_id.map(String::from),
+                    matches: matching.len(),
+                }),
+            }
+        })
+        .collect()
+}
+
+pub async fn create_security_group(
+    ec2_client: &Ec2Client,
+    name: &str,
+    vpc_id: Option<String>,
+    description: Option<String>,
+    dry_run: bool,
+) -> Result<()> {
+    let request = CreateSecurityGroupRequest {
+        group_name: name.into(),
+        description: description.unwrap_or_else(|| name.into()),
+        vpc_id,
+        dry_run: Some(dry_run),
+        ..Default::default()
+    };
+    match ec2_client.create_security_group(request).await {
+        Ok(result) => println!(
+            "Created security group {} ({})",
+            name,
+            result.group_id.as_deref().unwrap_or("N/A")
+        ),
+        Err(error) => util::dry_run_ok(error.into(), &format!("create security group {}", name))?,
+    }
+
+    Ok(())
+}
+
+pub async fn delete_security_group(
+    ec2_client: &Ec2Client,
+    group: &str,
+    yes: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let security_group = get_security_group(ec2_client, group).await?;
+    let group_id = security_group.group_id.unwrap_or_default();
+    let group_name = security_group.group_name.unwrap_or_default();
+
+    if !yes
+        && !dry_run
+        && !util::confirm(
+            &format!(
+                "This will delete security group {} ({}). Type the group name to confirm: ",
+                group_name, group_id
+            ),
+            &group_name,
+        )?
+    {
+        return Err(AwsInstanceError::SecurityGroupError {
+            group: group.into(),
+            message: "Confirmation did not match group name".into(),
+        });
+    }
+
+    delete_group_by_id(ec2_client, &group_id, &group_name, dry_run).await
+}
+
+async fn delete_group_by_id(
+    ec2_client: &Ec2Client,
+    group_id: &str,
+    group_name: &str,
+    dry_run: bool,
+) -> Result<()> {
+    let request = DeleteSecurityGroupRequest {
+        group_id: Some(group_id.into()),
+        dry_run: Some(dry_run),
+        ..Default::default()
+    };
+    match ec2_client.delete_security_group(request).await {
+        Ok(_) => println!("Deleted security group {} ({})", group_name, group_id),
+        Err(error) => {
+            util::dry_run_ok(error.into(), &format!("delete security group {}", group_id))?
+        }
+    }
+
+    Ok(())
+}
+
+/// What each security group is attached to, by group ID: instances (by name, or by ID if
+/// they have no name) and network interfaces that don't belong to an instance, such as load
+/// balancers' or Lambda functions'
+pub async fn group_usage(ec2_client: &Ec2Client) -> Result<HashMap<String, Vec<String>>> {
+    let mut usage: HashMap<String, Vec<String>> = HashMap::new();
+
+    for instance in util::get_all_instances(ec2_client).await? {
+        if util::get_state(&instance) == "terminated" {
+            continue;
+        }
+        let name = util::get_name(&instance);
+        let label = if name.is_empty() {
+            instance.instance_id.clone().unwrap_or_default()
+        } else {
+            name
+        };
+        for group in instance.security_groups.iter().flatten() {
+            if let Some(ref group_id) = group.group_id {
+                usage
+                    .entry(group_id.clone())
+                    .or_default()
+                    .push(label.clone());
+            }
+        }
+    }
+
+    let interfaces = ec2_client
+        .describe_network_interfaces(DescribeNetworkInterfacesRequest::default())
+        .await?
+        .network_interfaces
+        .unwrap_or_default();
+    for interface in interfaces {
+        // An instance's own interfaces were counted with the instance
+        if interface
+            .attachment
+            .as_ref()
+            .is_some_and(|attachment| attachment.instance_id.is_some())
+        {
+            continue;
+        }
+        let interface_id = interface.network_interface_id.unwrap_or_default();
+        let label = match interface
+            .description
+            .filter(|description| !description.is_empty())
+        {
+            Some(description) => format!("{} ({})", interface_id, description),
+            None => interface_id,
+        };
+        for group in interface.groups.iter().flatten() {
+            if let Some(ref group_id) = group.group_id {
+                usage
+                    .entry(group_id.clone())
+                    .or_default()
+                    .push(label.clone());
+            }
+        }
+    }
+
+    Ok(usage)
+}
+
+/// Groups which another group's rules refer to
+fn referenced_groups(security_groups: &[SecurityGroup]) -> HashSet<String> {
+    security_groups
+        .iter()
+        .flat_map(|group| {
+            group
+                .ip_permissions
+                .iter()
+                .chain(group.ip_permissions_egress.iter())
+                .flatten()
+                .flat_map(|permission| permission.user_id_group_pairs.iter().flatten())
+                .filter_map(|pair| pair.group_id.clone())
+                .filter(move |group_id| group.group_id.as_ref() != Some(group_id))
+        })
+        .collect()
+}
+
+/// List (and optionally delete) the groups nothing is attached to and no other group refers
+/// to. VPCs' default groups can't be deleted, so they're left out.
+pub async fn unused_security_groups(
+    ec2_client: &Ec2Client,
+    delete: bool,
+    yes: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let security_groups = ec2_client
+        .describe_security_groups(DescribeSecurityGroupsRequest::default())
+        .await?
+        .security_groups
+        .unwrap_or_default();
+    let usage = group_usage(ec2_client).await?;
+    let referenced = referenced_groups(&security_groups);
+
+    let unused: Vec<&SecurityGroup> = security_groups
+        .iter()
+        .filter(|group| group.group_name.as_deref() != Some("default"))
+        .filter(|group| {
+            group.group_id.as_ref().is_some_and(|group_id| {
+                !usage.contains_key(group_id) && !referenced.contains(group_id)
+            })
+        })
+        .collect();
+    if unused.is_empty() {
+        println!("No unused security groups found");
+        return Ok(());
+    }
+
+    println!(
+        "{0: <25} {1: <25} {2: <25} Description",
+        "Name", "Group ID", "VPC ID"
+    );
+    for group in &unused {
+        println!(
+            "{0: <25} {1: <25} {2: <25} {3}",
+            group.group_name.as_deref().unwrap_or("N/A"),
+            group.group_id.as_deref().unwrap_or("N/A"),
+            group.vpc_id.as_deref().unwrap_or("N/A"),
+            group.description.as_deref().unwrap_or("N/A"),
+        );
+    }
+
+    if !delete {
+        return Ok(());
+    }
+    if !yes
+        && !dry_run
+        && !util::confirm(
+            &format!(
+                "This will delete {} security group(s). Type 'delete' to confirm: ",
+                unused.len()
+            ),
+            "delete",
+        )?
+    {
+        return Err(AwsInstanceError::SecurityGroupError {
+            group: "unused groups".into(),
+            message: "Not confirmed".into(),
+        });
+    }
+    for group in unused {
+        delete_group_by_id(
+            ec2_client,
+            group.group_id.as_deref().unwrap_or_default(),
+            group.group_name.as_deref().unwrap_or("N/A"),
+            dry_run,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Add (or, if `allow` is false, remove) a rule in a group
+pub async fn change_rule(
+    ec2_client: &Ec2Client,
+    group: &str,
+    rule: &Rule,
+    egress: bool,
+    allow: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let group_id = get_security_group(ec2_client, group)
+        .await?
+        .group_id
+        .unwrap_or_default();
+    change_group_rule(ec2_client, &group_id, rule, egress, allow, dry_run).await
+}
+
+/// Add or remove a rule in the group with this ID
+pub async fn change_group_rule(
+    ec2_client: &Ec2Client,
+    group_id: &str,
+    rule: &Rule,
+    egress: bool,
+    allow: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let group_id = group_id.to_string();
+    let ip_permissions = Some(vec![rule.ip_permission()]);
+    let dry_run_flag = Some(dry_run);
+
+    let result = match (egress, allow) {
+        (false, true) => ec2_client
+            .authorize_security_group_ingress(AuthorizeSecurityGroupIngressRequest {
+                group_id: Some(group_id.clone()),
+                ip_permissions,
+                dry_run: dry_run_flag,
+                ..Default::default()
+            })
+            .await
+            .map(|_| ())
+            .map_err(AwsInstanceError::from),
+        (true, true) => ec2_client
+            .authorize_security_group_egress(AuthorizeSecurityGroupEgressRequest {
+                group_id: group_id.clone(),
+                ip_permissions,
+                dry_run: dry_run_flag,
+                ..Default::default()
+            })
+            .await
+            .map(|_| ())
+            .map_err(AwsInstanceError::from),
+        (false, false) => ec2_client
+            .revoke_security_group_ingress(RevokeSecurityGroupIngressRequest {
+                group_id: Some(group_id.clone()),
+                ip_permissions,
+                dry_run: dry_run_flag,
+                ..Default::default()
+            })
+            .await
+            .map(|_| ())
+            .map_err(AwsInstanceError::from),
+        (true, false) => ec2_client
+            .revoke_security_group_egress(RevokeSecurityGroupEgressRequest {
+                group_id: group_id.clone(),
+                ip_permissions,
+                dry_run: dry_run_flag,
+                ..Default::default()
+            })
+            .await
+            .map(|_| ())
+            .map_err(AwsInstanceError::from),
+    };
+
+    let direction = if egress { "egress" } else { "ingress" };
+    let action = if allow { "allow" } else { "revoke" };
+    match result {
+        Ok(()) => println!(
+            "{} {} {}: {}",
+            if allow { "Allowed" } else { "Revoked" },
+            direction,
+            group_id,
+            rule
+        ),
+        Err(error) => util::dry_run_ok(
+            error,
+            &format!("{} {} {}: {}", action, direction, group_id, rule),
+        )?,
+    }
+
+    Ok(())
+}
+
+/// The name of the person running us, to label their `allow-me` rules with
+fn local_user() -> String {
+    ["USER", "USERNAME"]
+        .iter()
+        .find_map(|variable| env::var(variable).ok().filter(|user| !user.is_empty()))
+        .unwrap_or_else(|| "unknown".into())
+}
+
+/// Whether one of a group's inbound rules already lets `rule`'s source reach its ports
+fn group_allows(security_group: &SecurityGroup, rule: &Rule) -> bool {
+    let cidr = match rule.source {
+        RuleSource::Cidr(ref cidr) => cidr,
+        RuleSource::Group(_) | RuleSource::PrefixList(_) => return false,
+    };
+    security_group
+        .ip_permissions
+        .iter()
+        .flatten()
+        .filter(|permission| {
+            permission.ip_protocol.as_deref() == Some("-1")
+                || (permission.ip_protocol.as_deref() == Some(rule.protocol.as_str())
+                    && permission.from_port.unwrap_or(-1) <= rule.ports.from
+                    && permission.to_port.unwrap_or(-1) >= rule.ports.to)
+        })
+        .any(|permission| {
+            permission
+                .ip_ranges
+                .iter()
+                .flatten()
+                .any(|range| range.cidr_ip.as_ref() == Some(cidr))
+                || permission
+                    .ipv_6_ranges
+                    .iter()
+                    .flatten()
+                    .any(|range| range.cidr_ipv_6.as_ref() == Some(cidr))
+        })
+}
+
+/// An inbound rule for one address, labelled so `prune-me` can find it later
+fn allow_me_rule(ip: IpAddr, ports: PortRange) -> Rule {
+    Rule {
+        protocol: "tcp".into(),
+        ports,
+        source: RuleSource::from_ip(ip),
+        description: Some(format!(
+            "{} {} {}",
+            ALLOW_ME_PREFIX,
+            local_user(),
+            Utc::now().format("%Y-%m-%dT%H:%M:%SZ")
+        )),
+    }
+}
+
+/// Let this machine's public IP address in to a port
+pub async fn allow_me(
+    ec2_client: &Ec2Client,
+    group: &str,
+    ports: PortRange,
+    echo_url: &str,
+    dry_run: bool,
+) -> Result<()> {
+    let security_group = get_security_group(ec2_client, group).await?;
+    let group_id = security_group.group_id.as_deref().unwrap_or_default();
+    let rule = allow_me_rule(util::public_ip(echo_url).await?, ports);
+
+    if group_allows(&security_group, &rule) {
+        println!("{} already allows {}", group_id, rule);
+        return Ok(());
+    }
+    change_group_rule(ec2_client, group_id, &rule, false, true, dry_run).await
+}
+
+/// Open port 22 to us on an instance's first security group, unless one of its groups
+/// already lets us in
+pub async fn allow_me_for_instance(
+    ec2_client: &Ec2Client,
+    instance: &Instance,
+    echo_url: &str,
+    dry_run: bool,
+) -> Result<()> {
+    let group_ids: Vec<String> = instance
+        .security_groups
+        .iter()
+        .flatten()
+        .filter_map(|group| group.group_id.clone())
+        .collect();
+    let first_group_id = match group_ids.first() {
+        Some(group_id) => group_id.clone(),
+        None => {
+            return Err(AwsInstanceError::SecurityGroupError {
+                group: "N/A".into(),
+                message: "Instance has no security groups to open".into(),
+            })
+        }
+    };
+    let request = DescribeSecurityGroupsRequest {
+        group_ids: Some(group_ids),
+        ..Default::default()
+    };
+    let security_groups = ec2_client
+        .describe_security_groups(request)
+        .await?
+        .security_groups
+        .unwrap_or_default();
+    let rule = allow_me_rule(
+        util::public_ip(echo_url).await?,
+        PortRange { from: 22, to: 22 },
+    );
+
+    if security_groups
+        .iter()
+        .any(|security_group| group_allows(security_group, &rule))
+    {
+        return Ok(());
+    }
+    change_group_rule(ec2_client, &first_group_id, &rule, false, true, dry_run).await
+}
+
+/// Remove the rules `allow-me` added for this user, except (unless `all` is set) the one for
+/// the address we have now
+pub async fn prune_me(
+    ec2_client: &Ec2Client,
+    group: &str,
+    echo_url: &str,
+    all: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let security_group = get_security_group(ec2_client, group).await?;
+    let group_id = security_group.group_id.clone().unwrap_or_default();
+    let current = if all {
+        None
+    } else {
+        Some(RuleSource::from_ip(util::public_ip(echo_url).await?))
+    };
+    let prefix = format!("{} {} ", ALLOW_ME_PREFIX, local_user());
+
+    let stale: Vec<Rule> = security_group
+        .ip_permissions
+        .iter()
+        .flatten()
+        .flat_map(Rule::from_permission)
+        .filter(|rule| {
+            rule.description
+                .as_deref()
+                .is_some_and(|description| description.starts_with(&prefix))
+        })
+        .filter(|rule| current.as_ref() != Some(&rule.source))
+        .map(|rule| Rule {
+            description: None,
+            ..rule
+        })
+        .collect();
+
+    if stale.is_empty() {
+        println!("No stale rules for {} in {}", local_user(), group_id);
+    }
+    for rule in stale {
+        change_group_rule(ec2_client, &group_id, &rule, false, false, dry_run).await?;
+    }
+
+    Ok(())
+}
+
+/// Change the description of an existing rule
+pub async fn update_rule_description(
+    ec2_client: &Ec2Client,
+    group_id: &str,
+    rule: &Rule,
+    egress: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let ip_permissions = vec![rule.ip_permission()];
+    let result = if egress {
+        ec2_client
+            .update_security_group_rule_descriptions_egress(
+                UpdateSecurityGroupRuleDescriptionsEgressRequest {
+                    group_id: Some(group_id.into()),
+                    ip_permissions,
+                    dry_run: Some(dry_run),
+                    ..Default::default()
+                },
+            )
+            .await
+            .map(|_| ())
+            .map_err(AwsInstanceError::from)
+    } else {
+        ec2_client
+            .update_security_group_rule_descriptions_ingress(
+                UpdateSecurityGroupRuleDescriptionsIngressRequest {
+                    group_id: Some(group_id.into()),
+                    ip_permissions,
+                    dry_run: Some(dry_run),
+                    ..Default::default()
+                },
+            )
+            .await
+            .map(|_| ())
+            .map_err(AwsInstanceError::from)
+    };
+
+    let direction = if egress { "egress" } else { "ingress" };
+    match result {
+        Ok(()) => println!(
+            "Updated description of {} {}: {}",
+            direction, group_id, rule
+        ),
+        Err(error) => util::dry_run_ok(
+            error,
+            &format!("update description of {} {}: {}", direction, group_id, rule),
+        )?,
+    }
+
+    Ok(())
+}
@@ -0,0 +1,263 @@
+use crate::commands::security_group::{
+    change_group_rule, format_icmp, get_security_group, is_icmp, parse_icmp,
+    update_rule_description, PortRange, Rule, RuleSource,
+};
+use crate::{AwsInstanceError, Result};
+use rusoto_ec2::{Ec2Client, IpPermission};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::str::FromStr;
+
+/// A security group's rules, as kept in a TOML file
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SecurityGroupFile {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub ingress: Vec<RuleEntry>,
+    #[serde(default)]
+    pub egress: Vec<RuleEntry>,
+}
+
+/// One rule, with exactly one of `cidr`, `group` or `prefix-list` saying where the traffic
+/// comes from (or goes to)
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RuleEntry {
+    pub protocol: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ports: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cidr: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefix_list: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+impl From<&Rule> for RuleEntry {
+    fn from(rule: &Rule) -> Self {
+        let mut entry = RuleEntry {
+            protocol: rule.protocol.clone(),
+            description: rule.description.clone(),
+            ..Default::default()
+        };
+        if is_icmp(&rule.protocol) {
+            entry.ports = Some(format_icmp(rule.ports));
+        } else if rule.protocol != "all" {
+            entry.ports = Some(rule.ports.to_string());
+        }
+        match rule.source {
+            RuleSource::Cidr(ref cidr) => entry.cidr = Some(cidr.clone()),
+            RuleSource::Group(ref group_id) => entry.group = Some(group_id.clone()),
+            RuleSource::PrefixList(ref prefix_list_id) => {
+                entry.prefix_list = Some(prefix_list_id.clone())
+            }
+        }
+        entry
+    }
+}
+
+impl RuleEntry {
+    async fn rule(&self, ec2_client: &Ec2Client) -> std::result::Result<Rule, String> {
+        let protocol = match self.protocol.to_lowercase().as_str() {
+            "-1" | "all" => "all".to_string(),
+            protocol => protocol.to_string(),
+        };
+        let ports = match (protocol.as_str(), &self.ports) {
+            ("all", _) => PortRange { from: -1, to: -1 },
+            (_, Some(ports)) if is_icmp(&protocol) => parse_icmp(ports)?,
+            (_, None) if is_icmp(&protocol) => PortRange { from: -1, to: -1 },
+            (_, Some(ports)) => PortRange::from_str(ports)?,
+            (_, None) => return Err(format!("A {} rule needs ports", protocol)),
+        };
+        let source = match (&self.cidr, &self.group, &self.prefix_list) {
+            (Some(cidr), None, None) => RuleSource::Cidr(cidr.clone()),
+            (None, Some(group), None) if group.starts_with("sg-") => {
+                RuleSource::Group(group.clone())
+            }
+            (None, Some(group), None) => RuleSource::Group(
+                get_security_group(ec2_client, group)
+                    .await
+                    .map_err(|error| error.to_string())?
+                    .group_id
+                    .unwrap_or_default(),
+            ),
+            (None, None, Some(prefix_list)) => RuleSource::PrefixList(prefix_list.clone()),
+            _ => return Err("A rule needs exactly one of cidr, group or prefix-list".into()),
+        };
+
+        Ok(Rule {
+            protocol,
+            ports,
+            source,
+            description: self.description.clone(),
+        })
+    }
+}
+
+// ICMP rules use the port fields for the ICMP type and code
+/// A change `apply` would make to a group
+#[derive(Debug)]
+enum Change {
+    Add(Rule),
+    Remove(Rule),
+    Describe(Rule),
+}
+
+pub fn read_security_group_file(path: &str) -> Result<SecurityGroupFile> {
+    let contents = fs::read_to_string(path).map_err(|error| AwsInstanceError::ConfigError {
+        path: path.into(),
+        line: None,
+        message: format!("Error reading file: {}", error),
+    })?;
+
+    toml::from_str(&contents).map_err(|error| AwsInstanceError::ConfigError {
+        path: path.into(),
+        line: error.line_col().map(|(line, _)| line + 1),
+        message: error.to_string(),
+    })
+}
+
+fn rules(permissions: &Option<Vec<IpPermission>>) -> Vec<Rule> {
+    permissions
+        .iter()
+        .flatten()
+        .flat_map(Rule::from_permission)
+        .collect()
+}
+
+/// Write a group's rules out as TOML
+pub async fn export_security_group(ec2_client: &Ec2Client, group: &str) -> Result<()> {
+    let security_group = get_security_group(ec2_client, group).await?;
+    let file = SecurityGroupFile {
+        name: security_group.group_name.clone().unwrap_or_default(),
+        group_id: security_group.group_id.clone(),
+        description: security_group.description.clone(),
+        ingress: rules(&security_group.ip_permissions)
+            .iter()
+            .map(RuleEntry::from)
+            .collect(),
+        egress: rules(&security_group.ip_permissions_egress)
+            .iter()
+            .map(RuleEntry::from)
+            .collect(),
+    };
+
+    let contents = toml::to_string(&file).map_err(|error| AwsInstanceError::Service {
+        message: format!("Error writing security group {}: {}", group, error),
+    })?;
+    print!("{}", contents);
+
+    Ok(())
+}
+
+fn changes(wanted: &[Rule], current: &[Rule]) -> Vec<Change> {
+    let mut changes = vec![];
+    for rule in current {
+        if !wanted.iter().any(|wanted| wanted.same_traffic(rule)) {
+            changes.push(Change::Remove(rule.clone()));
+        }
+    }
+    for rule in wanted {
+        match current.iter().find(|current| current.same_traffic(rule)) {
+            None => changes.push(Change::Add(rule.clone())),
+            Some(current) if current.description != rule.description => {
+                changes.push(Change::Describe(rule.clone()))
+            }
+            Some(_) => (),
+        }
+    }
+    changes
+}
+
+fn print_change(direction: &str, change: &Change) {
+    let (symbol, rule) = match change {
+        Change::Add(rule) => ("+", rule),
+        Change::Remove(rule) => ("-", rule),
+        Change::Describe(rule) => ("~", rule),
+    };
+    match rule.description {
+        Some(ref description) => println!(
+            "  {0} {1: <7} {2} ({3})",
+            symbol, direction, rule, description
+        ),
+        None => println!("  {0} {1: <7} {2}", symbol, direction, rule),
+    }
+}
+
+/// Show how a group's rules differ from a file, then (unless `plan` is set) change the group
+/// to match
+pub async fn apply_security_group(
+    ec2_client: &Ec2Client,
+    file: &SecurityGroupFile,
+    plan: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let group = file.group_id.as_deref().unwrap_or(&file.name);
+    let security_group = get_security_group(ec2_client, group).await?;
+    let group_id = security_group.group_id.clone().unwrap_or_default();
+
+    let mut all_changes = vec![];
+    for (egress, entries, permissions) in [
+        (false, &file.ingress, &security_group.ip_permissions),
+        (true, &file.egress, &security_group.ip_permissions_egress),
+    ] {
+        let mut wanted = vec![];
+        for entry in entries {
+            wanted.push(entry.rule(ec2_client).await.map_err(|message| {
+                AwsInstanceError::SecurityGroupError {
+                    group: group.into(),
+                    message,
+                }
+            })?);
+        }
+        for change in changes(&wanted, &rules(permissions)) {
+            all_changes.push((egress, change));
+        }
+    }
+
+    println!(
+        "Security group {} ({})",
+        security_group.group_name.as_deref().unwrap_or("N/A"),
+        group_id
+    );
+    if all_changes.is_empty() {
+        println!("  No changes");
+        return Ok(());
+    }
+    for (egress, change) in &all_changes {
+        print_change(if *egress { "egress" } else { "ingress" }, change);
+    }
+    if plan {
+        return Ok(());
+    }
+
+    // Add before removing, so traffic that's moving between rules isn't cut off
+    all_changes.sort_by_key(|(_, change)| matches!(change, Change::Remove(_)));
+    for (egress, change) in all_changes {
+        match change {
+            Change::Add(rule) => {
+                change_group_rule(ec2_client, &group_id, &rule, egress, true, dry_run).await?
+            }
+            Change::Remove(rule) => {
+                let rule = Rule {
+                    description: None,
+                    ..rule
+                };
+                change_group_rule(ec2_client, &group_id, &rule, egress, false, dry_run).await?
+            }
+            Change::Describe(rule) => {
+                update_rule_description(ec2_client, &group_id, &rule, egress, dry_run).await?
+            }
+        }
+    }
+
+    Ok(())
+}
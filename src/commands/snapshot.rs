@@ -0,0 +1,440 @@
+use crate::commands::volume::{describe_volume, wait_for_volume};
+use crate::{util, AwsInstanceError, Result};
+use chrono::Utc;
+use rusoto_ec2::{
+    AttachVolumeRequest, CreateSnapshotRequest, CreateVolumeRequest, DeleteSnapshotRequest,
+    DescribeSnapshotsRequest, DetachVolumeRequest, EbsInstanceBlockDeviceSpecification, Ec2,
+    Ec2Client, Filter, Instance, InstanceBlockDeviceMappingSpecification,
+    ModifyInstanceAttributeRequest, Snapshot, Tag, TagSpecification, Volume,
+};
+use std::collections::BTreeMap;
+
+// Tags tying a snapshot to the instance, set and device it was taken from
+const INSTANCE_TAG: &str = "aws-instance:instance";
+const LABEL_TAG: &str = "aws-instance:label";
+const DEVICE_TAG: &str = "aws-instance:device";
+
+fn tag_value<'a>(snapshot: &'a Snapshot, key: &str) -> Option<&'a str> {
+    snapshot
+        .tags
+        .iter()
+        .flatten()
+        .find(|tag| tag.key.as_deref() == Some(key))
+        .and_then(|tag| tag.value.as_deref())
+}
+
+async fn instance_by_name(ec2_client: &Ec2Client, name: &str) -> Result<Instance> {
+    util::get_instance_by_name(ec2_client, name)
+        .await?
+        .ok_or_else(|| AwsInstanceError::InstanceNotFoundError {
+            instance_name: name.into(),
+        })
+}
+
+/// Our snapshots, of one instance or of all of them
+async fn describe_snapshots(ec2_client: &Ec2Client, name: Option<&str>) -> Result<Vec<Snapshot>> {
+    let filter = match name {
+        Some(name) => Filter {
+            name: Some(format!("tag:{}", INSTANCE_TAG)),
+            values: Some(vec![name.into()]),
+        },
+        None => Filter {
+            name: Some("tag-key".into()),
+            values: Some(vec![INSTANCE_TAG.into()]),
+        },
+    };
+    let request = DescribeSnapshotsRequest {
+        owner_ids: Some(vec!["self".into()]),
+        filters: Some(vec![filter]),
+        ..Default::default()
+    };
+    Ok(ec2_client
+        .describe_snapshots(request)
+        .await?
+        .snapshots
+        .unwrap_or_default())
+}
+
+/// Snapshots grouped into sets by instance and label, newest set first within each instance
+fn snapshot_sets(snapshots: Vec<Snapshot>) -> Vec<((String, String), Vec<Snapshot>)> {
+    let mut sets: BTreeMap<(String, String), Vec<Snapshot>> = BTreeMap::new();
+    for snapshot in snapshots {
+        let key = (
+            tag_value(&snapshot, INSTANCE_TAG)
+                .unwrap_or_default()
+                .to_string(),
+            tag_value(&snapshot, LABEL_TAG)
+                .unwrap_or_default()
+                .to_string(),
+        );
+        sets.entry(key).or_default().push(snapshot);
+    }
+
+    let mut sets: Vec<((String, String), Vec<Snapshot>)> = sets.into_iter().collect();
+    for (_, snapshots) in sets.iter_mut() {
+        snapshots.sort_by(|a, b| tag_value(a, DEVICE_TAG).cmp(&tag_value(b, DEVICE_TAG)));
+    }
+    let started = |snapshots: &Vec<Snapshot>| {
+        snapshots
+            .iter()
+            .filter_map(|snapshot| snapshot.start_time.clone())
+            .min()
+            .unwrap_or_default()
+    };
+    sets.sort_by(|(a_key, a), (b_key, b)| {
+        a_key
+            .0
+            .cmp(&b_key.0)
+            .then_with(|| started(b).cmp(&started(a)))
+    });
+    sets
+}
+
+/// Snapshot every EBS volume attached to an instance, as one labelled set
+pub async fn create_snapshots(
+    ec2_client: &Ec2Client,
+    name: &str,
+    label: Option<String>,
+    dry_run: bool,
+) -> Result<()> {
+    let instance = instance_by_name(ec2_client, name).await?;
+    let label = label.unwrap_or_else(|| Utc::now().format("%Y%m%d-%H%M%S").to_string());
+    if describe_snapshots(ec2_client, Some(name))
+        .await?
+        .iter()
+        .any(|snapshot| tag_value(snapshot, LABEL_TAG) == Some(label.as_str()))
+    {
+        return Err(AwsInstanceError::SnapshotError {
+            instance_name: name.into(),
+            message: format!("There are already snapshots labelled {}", label),
+        });
+    }
+
+    let volumes: Vec<(String, String)> = instance
+        .block_device_mappings
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|mapping| Some((mapping.device_name?, mapping.ebs?.volume_id?)))
+        .collect();
+    if volumes.is_empty() {
+        return Err(AwsInstanceError::SnapshotError {
+            instance_name: name.into(),
+            message: "Instance has no EBS volumes".into(),
+        });
+    }
+
+    for (device, volume_id) in volumes {
+        let tags = [
+            ("Name", format!("{} {} {}", name, label, device)),
+            (INSTANCE_TAG, name.to_string()),
+            (LABEL_TAG, label.clone()),
+            (DEVICE_TAG, device.clone()),
+        ]
+        .into_iter()
+        .map(|(key, value)| Tag {
+            key: Some(key.into()),
+            value: Some(value),
+        })
+        .collect();
+        let request = CreateSnapshotRequest {
+            volume_id: volume_id.clone(),
+            description: Some(format!("{} of {} ({})", device, name, label)),
+            tag_specifications: Some(vec![TagSpecification {
+                resource_type: Some("snapshot".into()),
+                tags: Some(tags),
+            }]),
+            dry_run: Some(dry_run),
+            ..Default::default()
+        };
+        match ec2_client.create_snapshot(request).await {
+            Ok(snapshot) => println!(
+                "Creating snapshot {} of {} ({})",
+                snapshot.snapshot_id.as_deref().unwrap_or("N/A"),
+                device,
+                volume_id
+            ),
+            Err(error) => util::dry_run_ok(
+                error.into(),
+                &format!("snapshot {} ({}) as {}", device, volume_id, label),
+            )?,
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn list_snapshots(ec2_client: &Ec2Client, name: &str) -> Result<()> {
+    let sets = snapshot_sets(describe_snapshots(ec2_client, Some(name)).await?);
+    if sets.is_empty() {
+        println!("No snapshots found for {}", name);
+        return Ok(());
+    }
+
+    println!(
+        "{0: <20} {1: <25} {2: <12} {3: <8} {4: <12} Started",
+        "Label", "Snapshot ID", "Device", "Size", "State"
+    );
+    for ((_, label), snapshots) in sets {
+        for snapshot in snapshots {
+            println!(
+                "{0: <20} {1: <25} {2: <12} {3: <8} {4: <12} {5}",
+                label,
+                snapshot.snapshot_id.as_deref().unwrap_or("N/A"),
+                tag_value(&snapshot, DEVICE_TAG).unwrap_or("N/A"),
+                snapshot
+                    .volume_size
+                    .map(|size| format!("{} GiB", size))
+                    .unwrap_or_else(|| "N/A".into()),
+                match (snapshot.state.as_deref(), snapshot.progress.as_deref()) {
+                    (Some("pending"), Some(progress)) => format!("pending {}", progress),
+                    (state, _) => state.unwrap_or("N/A").to_string(),
+                },
+                snapshot.start_time.as_deref().unwrap_or("N/A"),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Delete all but the newest `keep` snapshot sets of each instance (or of one instance)
+pub async fn prune_snapshots(
+    ec2_client: &Ec2Client,
+    name: Option<&str>,
+    keep: usize,
+    yes: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let mut kept: BTreeMap<String, usize> = BTreeMap::new();
+    let mut prune = vec![];
+    for ((instance_name, label), snapshots) in
+        snapshot_sets(describe_snapshots(ec2_client, name).await?)
+    {
+        let count = kept.entry(instance_name.clone()).or_default();
+        if *count < keep {
+            *count += 1;
+        } else {
+            prune.push((instance_name, label, snapshots));
+        }
+    }
+    if prune.is_empty() {
+        println!("No snapshots to prune");
+        return Ok(());
+    }
+
+    for (instance_name, label, snapshots) in &prune {
+        println!(
+            "Pruning {} {} ({} snapshot(s))",
+            instance_name,
+            label,
+            snapshots.len()
+        );
+    }
+    let count: usize = prune.iter().map(|(_, _, snapshots)| snapshots.len()).sum();
+    if !yes
+        && !dry_run
+        && !util::confirm(
+            &format!(
+                "This will delete {} snapshot(s). Type 'delete' to confirm: ",
+                count
+            ),
+            "delete",
+        )?
+    {
+        return Err(AwsInstanceError::SnapshotError {
+            instance_name: name.unwrap_or("all instances").into(),
+            message: "Not confirmed".into(),
+        });
+    }
+
+    for (_, _, snapshots) in prune {
+        for snapshot in snapshots {
+            let snapshot_id = snapshot.snapshot_id.unwrap_or_default();
+            let request = DeleteSnapshotRequest {
+                snapshot_id: snapshot_id.clone(),
+                dry_run: Some(dry_run),
+            };
+            match ec2_client.delete_snapshot(request).await {
+                Ok(_) => println!("Deleted snapshot {}", snapshot_id),
+                Err(error) => {
+                    util::dry_run_ok(error.into(), &format!("delete snapshot {}", snapshot_id))?
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Replace a stopped instance's volumes with new ones made from a snapshot set. The old
+/// volumes are detached but kept, in case the restore was a mistake.
+pub async fn restore_snapshots(
+    ec2_client: &Ec2Client,
+    name: &str,
+    label: &str,
+    yes: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let error = |message: String| AwsInstanceError::SnapshotError {
+        instance_name: name.into(),
+        message,
+    };
+
+    let instance = instance_by_name(ec2_client, name).await?;
+    let state = util::get_state(&instance);
+    if state != "stopped" {
+        return Err(error(format!(
+            "Instance is {}, stop it before restoring",
+            state
+        )));
+    }
+    let instance_id = instance.instance_id.clone().unwrap_or_default();
+    let availability_zone = instance
+        .placement
+        .as_ref()
+        .and_then(|placement| placement.availability_zone.clone())
+        .ok_or_else(|| error("Instance has no availability zone".into()))?;
+
+    let snapshots: Vec<Snapshot> = describe_snapshots(ec2_client, Some(name))
+        .await?
+        .into_iter()
+        .filter(|snapshot| tag_value(snapshot, LABEL_TAG) == Some(label))
+        .collect();
+    if snapshots.is_empty() {
+        return Err(error(format!("No snapshots labelled {}", label)));
+    }
+    if let Some(snapshot) = snapshots
+        .iter()
+        .find(|snapshot| snapshot.state.as_deref() != Some("completed"))
+    {
+        return Err(error(format!(
+            "Snapshot {} is {}, wait for it to complete",
+            snapshot.snapshot_id.as_deref().unwrap_or("N/A"),
+            snapshot.state.as_deref().unwrap_or("N/A")
+        )));
+    }
+
+    // What's attached to each device now, and whether it goes when the instance does
+    let attached: BTreeMap<String, (String, bool)> = instance
+        .block_device_mappings
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|mapping| {
+            let ebs = mapping.ebs?;
+            Some((
+                mapping.device_name?,
+                (ebs.volume_id?, ebs.delete_on_termination.unwrap_or(false)),
+            ))
+        })
+        .collect();
+
+    if !yes
+        && !dry_run
+        && !util::confirm(
+            &format!(
+                "This will replace {} volume(s) on {} with the snapshots labelled {}. Type the instance name to confirm: ",
+                snapshots.len(),
+                name,
+                label
+            ),
+            name,
+        )?
+    {
+        return Err(error("Confirmation did not match instance name".into()));
+    }
+
+    for snapshot in snapshots {
+        let snapshot_id = snapshot.snapshot_id.clone().unwrap_or_default();
+        let device = tag_value(&snapshot, DEVICE_TAG)
+            .ok_or_else(|| error(format!("Snapshot {} has no device tag", snapshot_id)))?
+            .to_string();
+        let old_volume = attached.get(&device);
+
+        // Keep the type and performance of the volume we're replacing
+        let current = match old_volume {
+            Some((volume_id, _)) => describe_volume(ec2_client, volume_id).await?,
+            None => Volume::default(),
+        };
+        let provisioned = matches!(
+            current.volume_type.as_deref(),
+            Some("gp3") | Some("io1") | Some("io2")
+        );
+        let request = CreateVolumeRequest {
+            availability_zone: availability_zone.clone(),
+            snapshot_id: Some(snapshot_id.clone()),
+            iops: current.iops.filter(|_| provisioned),
+            throughput: current.throughput,
+            volume_type: current.volume_type,
+            tag_specifications: Some(vec![TagSpecification {
+                resource_type: Some("volume".into()),
+                tags: Some(vec![Tag {
+                    key: Some("Name".into()),
+                    value: Some(format!("{} {}", name, device)),
+                }]),
+            }]),
+            dry_run: Some(dry_run),
+            ..Default::default()
+        };
+        let new_volume_id = match ec2_client.create_volume(request).await {
+            Ok(volume) => volume.volume_id.unwrap_or_default(),
+            Err(create_error) => {
+                util::dry_run_ok(
+                    create_error.into(),
+                    &format!("restore {} on {} from {}", device, name, snapshot_id),
+                )?;
+                continue;
+            }
+        };
+        println!(
+            "Creating {} from {} for {}",
+            new_volume_id, snapshot_id, device
+        );
+        wait_for_volume(ec2_client, &new_volume_id, "available").await?;
+
+        if let Some((old_volume_id, _)) = old_volume {
+            let request = DetachVolumeRequest {
+                volume_id: old_volume_id.clone(),
+                instance_id: Some(instance_id.clone()),
+                ..Default::default()
+            };
+            ec2_client.detach_volume(request).await?;
+            println!("Detaching {} from {}", old_volume_id, device);
+            wait_for_volume(ec2_client, old_volume_id, "available").await?;
+        }
+
+        let request = AttachVolumeRequest {
+            volume_id: new_volume_id.clone(),
+            instance_id: instance_id.clone(),
+            device: device.clone(),
+            ..Default::default()
+        };
+        ec2_client.attach_volume(request).await?;
+        wait_for_volume(ec2_client, &new_volume_id, "in-use").await?;
+
+        if let Some((_, true)) = old_volume {
+            let request = ModifyInstanceAttributeRequest {
+                instance_id: instance_id.clone(),
+                block_device_mappings: Some(vec![InstanceBlockDeviceMappingSpecification {
+                    device_name: Some(device.clone()),
+                    ebs: Some(EbsInstanceBlockDeviceSpecification {
+                        delete_on_termination: Some(true),
+                        volume_id: Some(new_volume_id.clone()),
+                    }),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            };
+            ec2_client.modify_instance_attribute(request).await?;
+        }
+
+        match old_volume {
+            Some((old_volume_id, _)) => println!(
+                "Restored {} from {}, the old volume {} is detached and can be deleted",
+                device, snapshot_id, old_volume_id
+            ),
+            None => println!("Restored {} from {}", device, snapshot_id),
+        }
+    }
+
+    Ok(())
+}
@@ -1,8 +1,9 @@
+use crate::commands::keypair::find_key;
 use crate::{cmdline::OsNames, util, AwsInstanceError, Result};
 use lazy_static::lazy_static;
 use rusoto_ec2::Ec2Client;
 use std::collections::HashMap;
-use std::process::{exit, Command};
+use std::process::Command;
 
 lazy_static! {
     static ref USERNAME_MAP: HashMap<OsNames, &'static str> = {
@@ -55,17 +56,40 @@ pub async fn ssh(
         }
     };
 
-    let mut child = Command::new("ssh")
+    // Fall back to the key we saved when we created the instance's key pair
+    let mut key_opts = vec![];
+    if !ssh_opts.contains(&"-i".to_string()) {
+        if let Some(path) = instance.key_name.as_deref().and_then(find_key) {
+            key_opts.push("-i".to_string());
+            key_opts.push(path.display().to_string());
+        }
+    }
+
+    let status = Command::new("ssh")
         .arg(ip_address)
         .args(vec!["-l", username])
+        .args(key_opts)
         .args(ssh_opts)
-        .spawn()
-        .expect("SSH Error");
-
-    let status = child.wait().expect("failed to wait on child");
+        .status()
+        .map_err(|error| AwsInstanceError::SshError {
+            instance_name: name.into(),
+            message: format!("Error running ssh: {}", error),
+        })?;
 
+    // ssh exits with 255 for its own errors, otherwise with the remote command's status
     match status.code() {
-        Some(code) => exit(code),
-        None => exit(1),
+        Some(0) => Ok(()),
+        Some(255) => Err(AwsInstanceError::SshError {
+            instance_name: name.into(),
+            message: "ssh exited with status 255".into(),
+        }),
+        Some(code) => Err(AwsInstanceError::RemoteCommandError {
+            instance_name: name.into(),
+            status: code,
+        }),
+        None => Err(AwsInstanceError::SshError {
+            instance_name: name.into(),
+            message: "ssh was killed by a signal".into(),
+        }),
     }
 }
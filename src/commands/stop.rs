@@ -1,16 +1,20 @@
 use crate::{print_state_changes, util, AwsInstanceError, Result};
 use rusoto_ec2::{Ec2, Ec2Client};
 
-pub async fn stop(ec2_client: &Ec2Client, name: &str) -> Result<()> {
+pub async fn stop(ec2_client: &Ec2Client, name: &str, dry_run: bool) -> Result<()> {
     match util::get_instance_by_name(ec2_client, name).await? {
         Some(instance) => {
             let instance_id = instance.instance_id.unwrap();
             let request = rusoto_ec2::StopInstancesRequest {
                 instance_ids: vec![instance_id],
+                dry_run: Some(dry_run),
                 ..Default::default()
             };
 
-            let result = ec2_client.stop_instances(request).await?;
+            let result = match ec2_client.stop_instances(request).await {
+                Ok(result) => result,
+                Err(error) => return util::dry_run_ok(error.into(), &format!("stop {}", name)),
+            };
             if let Some(state_changes) = result.stopping_instances {
                 print_state_changes(state_changes);
             } else {
@@ -21,9 +25,8 @@ pub async fn stop(ec2_client: &Ec2Client, name: &str) -> Result<()> {
             }
         }
         None => {
-            return Err(AwsInstanceError::StopInstanceError {
+            return Err(AwsInstanceError::InstanceNotFoundError {
                 instance_name: name.into(),
-                message: "Instance not found".into(),
             })
         }
     }
@@ -0,0 +1,326 @@
+use crate::{util, AwsInstanceError, Result};
+use rusoto_ec2::{
+    AttachVolumeRequest, CreateVolumeRequest, DescribeVolumesModificationsRequest,
+    DescribeVolumesRequest, DetachVolumeRequest, Ec2, Ec2Client, Filter, Instance,
+    ModifyVolumeRequest, Volume,
+};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+const WAIT_INTERVAL: Duration = Duration::from_secs(5);
+const WAIT_TIMEOUT: Duration = Duration::from_secs(1800);
+
+async fn instance_by_name(ec2_client: &Ec2Client, name: &str) -> Result<Instance> {
+    util::get_instance_by_name(ec2_client, name)
+        .await?
+        .ok_or_else(|| AwsInstanceError::InstanceNotFoundError {
+            instance_name: name.into(),
+        })
+}
+
+pub async fn describe_volume(ec2_client: &Ec2Client, volume_id: &str) -> Result<Volume> {
+    let request = DescribeVolumesRequest {
+        volume_ids: Some(vec![volume_id.into()]),
+        ..Default::default()
+    };
+    ec2_client
+        .describe_volumes(request)
+        .await?
+        .volumes
+        .and_then(|volumes| volumes.into_iter().next())
+        .ok_or_else(|| AwsInstanceError::VolumeError {
+            volume_id: volume_id.into(),
+            message: "Not found".into(),
+        })
+}
+
+/// Create an empty volume in the same availability zone as an instance, so it can be attached
+pub async fn create_volume(
+    ec2_client: &Ec2Client,
+    size: i64,
+    volume_type: &str,
+    az_of: &str,
+    dry_run: bool,
+) -> Result<()> {
+    let instance = instance_by_name(ec2_client, az_of).await?;
+    let availability_zone = instance
+        .placement
+        .and_then(|placement| placement.availability_zone)
+        .ok_or_else(|| AwsInstanceError::Validation {
+            message: format!("Instance {} has no availability zone", az_of),
+        })?;
+
+    let request = CreateVolumeRequest {
+        availability_zone: availability_zone.clone(),
+        size: Some(size),
+        volume_type: Some(volume_type.into()),
+        dry_run: Some(dry_run),
+        ..Default::default()
+    };
+    match ec2_client.create_volume(request).await {
+        Ok(volume) => println!(
+            "Created {} GiB {} volume {} in {}",
+            size,
+            volume_type,
+            volume.volume_id.as_deref().unwrap_or("N/A"),
+            availability_zone
+        ),
+        Err(error) => util::dry_run_ok(
+            error.into(),
+            &format!(
+                "create {} GiB {} volume in {}",
+                size, volume_type, availability_zone
+            ),
+        )?,
+    }
+
+    Ok(())
+}
+
+pub async fn attach_volume(
+    ec2_client: &Ec2Client,
+    volume_id: &str,
+    name: &str,
+    device: &str,
+    dry_run: bool,
+) -> Result<()> {
+    let instance = instance_by_name(ec2_client, name).await?;
+    let request = AttachVolumeRequest {
+        volume_id: volume_id.into(),
+        instance_id: instance.instance_id.unwrap_or_default(),
+        device: device.into(),
+        dry_run: Some(dry_run),
+    };
+    match ec2_client.attach_volume(request).await {
+        Ok(attachment) => println!(
+            "Attaching {} to {} as {}: {}",
+            volume_id,
+            name,
+            device,
+            attachment.state.as_deref().unwrap_or("N/A")
+        ),
+        Err(error) => util::dry_run_ok(
+            error.into(),
+            &format!("attach {} to {} as {}", volume_id, name, device),
+        )?,
+    }
+
+    Ok(())
+}
+
+pub async fn detach_volume(
+    ec2_client: &Ec2Client,
+    volume_id: &str,
+    force: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let request = DetachVolumeRequest {
+        volume_id: volume_id.into(),
+        force: force.then_some(true),
+        dry_run: Some(dry_run),
+        ..Default::default()
+    };
+    match ec2_client.detach_volume(request).await {
+        Ok(attachment) => println!(
+            "Detaching {} from {}: {}",
+            volume_id,
+            attachment.instance_id.as_deref().unwrap_or("N/A"),
+            attachment.state.as_deref().unwrap_or("N/A")
+        ),
+        Err(error) => util::dry_run_ok(error.into(), &format!("detach {}", volume_id))?,
+    }
+
+    Ok(())
+}
+
+/// Grow a volume, then wait until the new size can be used
+pub async fn resize_volume(
+    ec2_client: &Ec2Client,
+    volume_id: &str,
+    size: i64,
+    dry_run: bool,
+) -> Result<()> {
+    let volume = describe_volume(ec2_client, volume_id).await?;
+    let current_size = volume.size.unwrap_or_default();
+    if size <= current_size {
+        return Err(AwsInstanceError::VolumeError {
+            volume_id: volume_id.into(),
+            message: format!(
+                "Volume is already {} GiB, and volumes can only grow",
+                current_size
+            ),
+        });
+    }
+
+    let request = ModifyVolumeRequest {
+        volume_id: volume_id.into(),
+        size: Some(size),
+        dry_run: Some(dry_run),
+        ..Default::default()
+    };
+    if let Err(error) = ec2_client.modify_volume(request).await {
+        return util::dry_run_ok(
+            error.into(),
+            &format!(
+                "resize {} from {} GiB to {} GiB",
+                volume_id, current_size, size
+            ),
+        );
+    }
+    println!(
+        "Resizing {} from {} GiB to {} GiB",
+        volume_id, current_size, size
+    );
+
+    wait_for_modification(ec2_client, volume_id).await?;
+    println!(
+        "{} is now {} GiB. Grow the partition and filesystem on the instance to use it.",
+        volume_id, size
+    );
+
+    Ok(())
+}
+
+// The new size can be used once the modification reaches "optimizing", which can take
+// much longer to finish
+async fn wait_for_modification(ec2_client: &Ec2Client, volume_id: &str) -> Result<()> {
+    let started = Instant::now();
+    loop {
+        let request = DescribeVolumesModificationsRequest {
+            volume_ids: Some(vec![volume_id.into()]),
+            ..Default::default()
+        };
+        let modification = ec2_client
+            .describe_volumes_modifications(request)
+            .await?
+            .volumes_modifications
+            .and_then(|modifications| modifications.into_iter().next());
+        let (state, progress, message) = match modification {
+            Some(modification) => (
+                modification.modification_state,
+                modification.progress,
+                modification.status_message,
+            ),
+            None => (None, None, None),
+        };
+        match state.as_deref() {
+            Some("optimizing") | Some("completed") => return Ok(()),
+            Some("failed") => {
+                return Err(AwsInstanceError::VolumeError {
+                    volume_id: volume_id.into(),
+                    message: format!(
+                        "Resize failed: {}",
+                        message.unwrap_or_else(|| "no reason given".into())
+                    ),
+                })
+            }
+            _ => (),
+        }
+        if started.elapsed() > WAIT_TIMEOUT {
+            return Err(AwsInstanceError::VolumeError {
+                volume_id: volume_id.into(),
+                message: format!("Resize not done after {} seconds", WAIT_TIMEOUT.as_secs()),
+            });
+        }
+        println!(
+            "Waiting for {}: {} ({}%)",
+            volume_id,
+            state.as_deref().unwrap_or("N/A"),
+            progress.unwrap_or_default()
+        );
+        tokio::time::sleep(WAIT_INTERVAL).await;
+    }
+}
+
+/// Wait for a volume to reach a state, e.g. "available" once it's created or detached
+pub async fn wait_for_volume(ec2_client: &Ec2Client, volume_id: &str, state: &str) -> Result<()> {
+    let started = Instant::now();
+    loop {
+        let volume = describe_volume(ec2_client, volume_id).await?;
+        match volume.state.as_deref() {
+            Some(current) if current == state => return Ok(()),
+            Some("error") => {
+                return Err(AwsInstanceError::VolumeError {
+                    volume_id: volume_id.into(),
+                    message: "Volume is in the error state".into(),
+                })
+            }
+            _ => (),
+        }
+        if started.elapsed() > WAIT_TIMEOUT {
+            return Err(AwsInstanceError::VolumeError {
+                volume_id: volume_id.into(),
+                message: format!("Not {} after {} seconds", state, WAIT_TIMEOUT.as_secs()),
+            });
+        }
+        println!("Waiting for {} to be {}", volume_id, state);
+        tokio::time::sleep(WAIT_INTERVAL).await;
+    }
+}
+
+/// List volumes, or just the ones attached to one instance
+pub async fn list_volumes(ec2_client: &Ec2Client, name: &Option<String>) -> Result<()> {
+    let mut request = DescribeVolumesRequest::default();
+    if let Some(name) = name {
+        let instance = instance_by_name(ec2_client, name).await?;
+        request.filters = Some(vec![Filter {
+            name: Some("attachment.instance-id".into()),
+            values: Some(vec![instance.instance_id.unwrap_or_default()]),
+        }]);
+    }
+    let volumes = ec2_client
+        .describe_volumes(request)
+        .await?
+        .volumes
+        .unwrap_or_default();
+
+    let instance_names: HashMap<String, String> = util::get_all_instances(ec2_client)
+        .await?
+        .into_iter()
+        .filter_map(|instance| {
+            let name = util::get_name(&instance);
+            instance.instance_id.map(|instance_id| (instance_id, name))
+        })
+        .collect();
+
+    println!(
+        "{0: <25} {1: <8} {2: <8} {3: <12} {4: <15} Attached to",
+        "Volume ID", "Size", "Type", "State", "Zone"
+    );
+    for volume in volumes {
+        let attachments: Vec<String> = volume
+            .attachments
+            .iter()
+            .flatten()
+            .map(|attachment| {
+                let instance_id = attachment.instance_id.clone().unwrap_or_default();
+                format!(
+                    "{} ({})",
+                    instance_names
+                        .get(&instance_id)
+                        .filter(|name| !name.is_empty())
+                        .unwrap_or(&instance_id),
+                    attachment.device.as_deref().unwrap_or("N/A")
+                )
+            })
+            .collect();
+        println!(
+            "{0: <25} {1: <8} {2: <8} {3: <12} {4: <15} {5}",
+            volume.volume_id.as_deref().unwrap_or("N/A"),
+            volume
+                .size
+                .map(|size| format!("{} GiB", size))
+                .unwrap_or_else(|| "N/A".into()),
+            volume.volume_type.as_deref().unwrap_or("N/A"),
+            volume.state.as_deref().unwrap_or("N/A"),
+            volume.availability_zone.as_deref().unwrap_or("N/A"),
+            if attachments.is_empty() {
+                "None".to_string()
+            } else {
+                attachments.join(", ")
+            }
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,406 @@
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use log::debug;
+use rusoto_core::request::BufferedHttpResponse;
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::{Client, HttpClient, Region};
+use rusoto_credential::{
+    AwsCredentials, ContainerProvider, CredentialsError, EnvironmentProvider,
+    InstanceMetadataProvider, ProfileProvider, ProvideAwsCredentials, StaticProvider,
+};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+use crate::profile::{get_cache_dir, ConfigFileReader, ConfigValue};
+use crate::{util, AwsInstanceError, Result};
+
+// Credentials are refreshed when they have less than this long left
+const EXPIRY_MARGIN_MINUTES: i64 = 5;
+
+// Limit how far we follow source_profile, in case of loops
+const MAX_SOURCE_PROFILE_DEPTH: usize = 8;
+
+/// The settings in an AWS config file profile which need more than rusoto's `ProfileProvider`
+const SPECIAL_KEYS: &[&str] = &["role_arn", "sso_session", "sso_start_url", "sso_account_id"];
+
+#[derive(Debug, Deserialize, Serialize)]
+struct CachedCredentials {
+    #[serde(rename = "AccessKeyId")]
+    access_key_id: String,
+
+    #[serde(rename = "SecretAccessKey")]
+    secret_access_key: String,
+
+    #[serde(rename = "SessionToken")]
+    session_token: Option<String>,
+
+    #[serde(rename = "Expiration")]
+    expiration: DateTime<Utc>,
+}
+
+impl CachedCredentials {
+    fn is_fresh(&self) -> bool {
+        self.expiration > Utc::now() + Duration::minutes(EXPIRY_MARGIN_MINUTES)
+    }
+
+    fn into_credentials(self) -> AwsCredentials {
+        AwsCredentials::new(
+            self.access_key_id,
+            self.secret_access_key,
+            self.session_token,
+            Some(self.expiration),
+        )
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AssumeRoleResponse {
+    #[serde(rename = "AssumeRoleResult")]
+    result: AssumeRoleResult,
+}
+
+#[derive(Debug, Deserialize)]
+struct AssumeRoleResult {
+    #[serde(rename = "Credentials")]
+    credentials: CachedCredentials,
+}
+
+#[derive(Debug, Deserialize)]
+struct SsoTokenCache {
+    #[serde(rename = "accessToken")]
+    access_token: String,
+
+    #[serde(rename = "expiresAt")]
+    expires_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SsoCredentialsResponse {
+    #[serde(rename = "roleCredentials")]
+    role_credentials: SsoRoleCredentials,
+}
+
+#[derive(Debug, Deserialize)]
+struct SsoRoleCredentials {
+    #[serde(rename = "accessKeyId")]
+    access_key_id: String,
+
+    #[serde(rename = "secretAccessKey")]
+    secret_access_key: String,
+
+    #[serde(rename = "sessionToken")]
+    session_token: Option<String>,
+
+    // Milliseconds since the epoch
+    expiration: i64,
+}
+
+fn credentials_error<S: ToString>(message: S) -> AwsInstanceError {
+    AwsInstanceError::Credentials {
+        source: CredentialsError::new(message),
+    }
+}
+
+/// Get credentials for profiles which assume a role or use SSO, prompting for an MFA code
+/// if the profile needs one. Returns `None` for profiles rusoto can handle by itself.
+pub async fn get_credentials_provider(
+    config_file: &ConfigFileReader,
+    profile_name: &str,
+    region: &Region,
+    sts_endpoint: Option<&str>,
+) -> Result<Option<StaticProvider>> {
+    let needs_us = config_file
+        .aws_section(profile_name)
+        .map(|section| SPECIAL_KEYS.iter().any(|key| section.contains_key(*key)))
+        .unwrap_or(false);
+    if !needs_us {
+        return Ok(None);
+    }
+
+    let sts_region = match sts_endpoint {
+        Some(endpoint) => Region::Custom {
+            name: region.name().into(),
+            endpoint: endpoint.into(),
+        },
+        None => region.clone(),
+    };
+
+    let credentials = resolve(config_file, profile_name.to_string(), sts_region, 0).await?;
+    Ok(Some(StaticProvider::new(
+        credentials.aws_access_key_id().into(),
+        credentials.aws_secret_access_key().into(),
+        credentials.token().clone(),
+        credentials
+            .expires_at()
+            .map(|expires_at| (expires_at - Utc::now()).num_seconds()),
+    )))
+}
+
+fn resolve<'a>(
+    config_file: &'a ConfigFileReader,
+    profile_name: String,
+    sts_region: Region,
+    depth: usize,
+) -> BoxFuture<'a, Result<AwsCredentials>> {
+    async move {
+        if depth > MAX_SOURCE_PROFILE_DEPTH {
+            return Err(credentials_error(format!(
+                "Too many levels of source_profile at profile {}",
+                profile_name
+            )));
+        }
+
+        let section = config_file
+            .aws_section(&profile_name)
+            .cloned()
+            .unwrap_or_default();
+        let setting = |key: &str| {
+            section
+                .get(key)
+                .map(|value: &ConfigValue| value.value.clone())
+        };
+
+        if let Some(role_arn) = setting("role_arn") {
+            let cache_key = format!("{}-{}", profile_name, role_arn);
+            if let Some(cached) = read_cache(&cache_key) {
+                return Ok(cached);
+            }
+
+            let source_credentials = match (setting("source_profile"), setting("credential_source"))
+            {
+                (Some(source_profile), _) => {
+                    resolve(config_file, source_profile, sts_region.clone(), depth + 1).await?
+                }
+                (None, Some(credential_source)) => match credential_source.as_str() {
+                    "Environment" => EnvironmentProvider::default().credentials().await?,
+                    "Ec2InstanceMetadata" => InstanceMetadataProvider::new().credentials().await?,
+                    "EcsContainer" => ContainerProvider::new().credentials().await?,
+                    other => {
+                        return Err(credentials_error(format!(
+                            "Unknown credential_source '{}' in profile {}",
+                            other, profile_name
+                        )))
+                    }
+                },
+                (None, None) => {
+                    return Err(credentials_error(format!(
+                        "Profile {} has a role_arn but no source_profile or credential_source",
+                        profile_name
+                    )))
+                }
+            };
+
+            let credentials = assume_role(
+                &source_credentials,
+                &sts_region,
+                &role_arn,
+                setting("role_session_name"),
+                setting("external_id"),
+                setting("duration_seconds"),
+                setting("mfa_serial"),
+            )
+            .await?;
+            write_cache(&cache_key, &credentials);
+            return Ok(credentials.into_credentials());
+        }
+
+        if section.contains_key("sso_account_id") {
+            return sso_credentials(config_file, &profile_name, setting).await;
+        }
+
+        // Static keys or credential_process, which rusoto handles
+        let mut provider = ProfileProvider::new()?;
+        provider.set_profile(profile_name);
+        Ok(provider.credentials().await?)
+    }
+    .boxed()
+}
+
+async fn assume_role(
+    source_credentials: &AwsCredentials,
+    sts_region: &Region,
+    role_arn: &str,
+    role_session_name: Option<String>,
+    external_id: Option<String>,
+    duration_seconds: Option<String>,
+    mfa_serial: Option<String>,
+) -> Result<CachedCredentials> {
+    let mut request = SignedRequest::new("GET", "sts", sts_region, "/");
+    request.add_param("Action", "AssumeRole");
+    request.add_param("Version", "2011-06-15");
+    request.add_param("RoleArn", role_arn);
+    request.add_param(
+        "RoleSessionName",
+        &role_session_name.unwrap_or_else(|| format!("aws-instance-{}", Utc::now().timestamp())),
+    );
+    if let Some(external_id) = external_id {
+        request.add_param("ExternalId", &external_id);
+    }
+    if let Some(duration_seconds) = duration_seconds {
+        request.add_param("DurationSeconds", &duration_seconds);
+    }
+    if let Some(mfa_serial) = mfa_serial {
+        let token_code = util::prompt(&format!("Enter MFA code for {}: ", mfa_serial))?;
+        request.add_param("SerialNumber", &mfa_serial);
+        request.add_param("TokenCode", &token_code);
+    }
+
+    debug!("Assuming role {} via {}", role_arn, request.hostname());
+    let client = Client::new_with(
+        StaticProvider::from(source_credentials.clone()),
+        HttpClient::new().unwrap(),
+    );
+    let response = dispatch(&client, request).await?;
+    let response: AssumeRoleResponse = serde_xml_rs::de::from_str(response.body_as_str())
+        .map_err(|error| credentials_error(format!("Error parsing STS response: {}", error)))?;
+
+    Ok(response.result.credentials)
+}
+
+async fn sso_credentials<F>(
+    config_file: &ConfigFileReader,
+    profile_name: &str,
+    setting: F,
+) -> Result<AwsCredentials>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    // Newer configs keep the start URL and region in an [sso-session] section
+    let session = setting("sso_session");
+    let session_section = session
+        .as_ref()
+        .and_then(|session| config_file.aws_section(&format!("sso-session {}", session)));
+    let session_setting = |key: &str| {
+        session_section
+            .and_then(|section| section.get(key))
+            .map(|value| value.value.clone())
+            .or_else(|| setting(key))
+    };
+
+    let missing = |key: &str| {
+        credentials_error(format!(
+            "Profile {} is missing the {} setting",
+            profile_name, key
+        ))
+    };
+    let start_url = session_setting("sso_start_url").ok_or_else(|| missing("sso_start_url"))?;
+    let sso_region = session_setting("sso_region").ok_or_else(|| missing("sso_region"))?;
+    let account_id = setting("sso_account_id").ok_or_else(|| missing("sso_account_id"))?;
+    let role_name = setting("sso_role_name").ok_or_else(|| missing("sso_role_name"))?;
+
+    let cache_key = format!("{}-{}-{}", profile_name, account_id, role_name);
+    if let Some(cached) = read_cache(&cache_key) {
+        return Ok(cached);
+    }
+
+    // The AWS CLI names its token cache after the SHA1 of the session name or start URL
+    let token_key = session.unwrap_or_else(|| start_url.clone());
+    let mut token_path = dirs::home_dir().expect("Home directory not found");
+    token_path.push(".aws");
+    token_path.push("sso");
+    token_path.push("cache");
+    token_path.push(format!(
+        "{}.json",
+        sha1_smol::Sha1::from(&token_key).digest()
+    ));
+
+    let login_message = format!(
+        "SSO session for profile {} has expired or is missing, run 'aws sso login --profile {}'",
+        profile_name, profile_name
+    );
+    let token: SsoTokenCache = fs::read_to_string(&token_path)
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .ok_or_else(|| credentials_error(&login_message))?;
+    let token_expiry = DateTime::parse_from_rfc3339(&token.expires_at.replace("UTC", "Z"))
+        .map_err(|_| credentials_error(&login_message))?;
+    if token_expiry < Utc::now() {
+        return Err(credentials_error(login_message));
+    }
+
+    let mut request = SignedRequest::new(
+        "GET",
+        "sso",
+        &Region::Custom {
+            name: sso_region.clone(),
+            endpoint: format!("https://portal.sso.{}.amazonaws.com", sso_region),
+        },
+        "/federation/credentials",
+    );
+    request.add_param("account_id", &account_id);
+    request.add_param("role_name", &role_name);
+    request.add_header("x-amz-sso_bearer_token", &token.access_token);
+
+    debug!(
+        "Getting SSO credentials for {} in {}",
+        role_name, account_id
+    );
+    let client = Client::new_not_signing(HttpClient::new().unwrap());
+    let response = dispatch(&client, request).await?;
+    let response: SsoCredentialsResponse = serde_json::from_str(response.body_as_str())?;
+
+    let credentials = CachedCredentials {
+        access_key_id: response.role_credentials.access_key_id,
+        secret_access_key: response.role_credentials.secret_access_key,
+        session_token: response.role_credentials.session_token,
+        expiration: Utc
+            .timestamp_millis_opt(response.role_credentials.expiration)
+            .single()
+            .unwrap_or_else(Utc::now),
+    };
+    write_cache(&cache_key, &credentials);
+
+    Ok(credentials.into_credentials())
+}
+
+async fn dispatch(client: &Client, request: SignedRequest) -> Result<BufferedHttpResponse> {
+    let mut response = client
+        .sign_and_dispatch(request)
+        .await
+        .map_err(|error| credentials_error(format!("{:?}", error)))?;
+    let response = response
+        .buffer()
+        .await
+        .map_err(|error| AwsInstanceError::HttpDispatch { source: error })?;
+
+    if response.status.is_success() {
+        Ok(response)
+    } else {
+        Err(response.into())
+    }
+}
+
+fn cache_path(cache_key: &str) -> PathBuf {
+    let mut path = get_cache_dir();
+    path.push("credentials");
+    path.push(format!(
+        "{}.json",
+        sha1_smol::Sha1::from(cache_key).digest()
+    ));
+    path
+}
+
+fn read_cache(cache_key: &str) -> Option<AwsCredentials> {
+    let contents = fs::read_to_string(cache_path(cache_key)).ok()?;
+    let cached: CachedCredentials = serde_json::from_str(&contents).ok()?;
+    if cached.is_fresh() {
+        debug!("Using cached credentials for {}", cache_key);
+        Some(cached.into_credentials())
+    } else {
+        None
+    }
+}
+
+// Failing to cache isn't fatal, it just means we'll ask STS again next time
+fn write_cache(cache_key: &str, credentials: &CachedCredentials) {
+    let path = cache_path(cache_key);
+    let result = serde_json::to_string(credentials)
+        .map_err(std::io::Error::from)
+        .and_then(|contents| util::write_private_file(&path, contents.as_bytes(), true));
+
+    if let Err(error) = result {
+        debug!("Error caching credentials in {}: {}", path.display(), error);
+    }
+}
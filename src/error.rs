@@ -1,11 +1,14 @@
-use rusoto_core::request::HttpDispatchError;
+use rusoto_core::request::{BufferedHttpResponse, HttpDispatchError};
 use rusoto_core::RusotoError;
 use rusoto_credential::CredentialsError;
 use serde::{self, Deserialize};
+use serde_json::{json, Value};
 use snafu::Snafu;
 use std::convert::From;
 use std::fmt::Display;
 
+use crate::retry::THROTTLING_CODES;
+
 pub type Result<T> = std::result::Result<T, AwsInstanceError>;
 
 #[derive(Debug, Snafu)]
@@ -30,12 +33,33 @@ pub enum AwsInstanceError {
     #[snafu(display("Validation error: {}", message))]
     Validation { message: String },
 
-    #[snafu(display("Error with request ID {}: {}", request_id, errors[0]))]
+    #[snafu(display(
+        "Error with request ID {}: {}",
+        request_id.as_deref().unwrap_or("N/A"),
+        format_errors(errors)
+    ))]
     Unknown {
         errors: Vec<AwsXmlError>,
-        request_id: String,
+        request_id: Option<String>,
+    },
+
+    #[snafu(display("Unexpected response from AWS (HTTP status {}): {}", status, body))]
+    UnexpectedResponse { status: u16, body: String },
+
+    #[snafu(display(
+        "Not authorized to perform this operation (request ID {}): {}\nHint: {}",
+        request_id.as_deref().unwrap_or("N/A"),
+        message,
+        hint_for_code("UnauthorizedOperation").unwrap_or_default()
+    ))]
+    Unauthorized {
+        message: String,
+        request_id: Option<String>,
     },
 
+    #[snafu(display("Dry run succeeded: {}", message))]
+    DryRunOperation { message: String },
+
     #[snafu(display("Error describing instances: {}", source))]
     DescribeInstancesError {
         source: rusoto_ec2::DescribeInstancesError,
@@ -70,15 +94,71 @@ pub enum AwsInstanceError {
         message: String,
     },
 
+    #[snafu(display("Error with security group {}: {}", group, message))]
+    SecurityGroupError { group: String, message: String },
+
+    #[snafu(display(
+        "{}",
+        security_group_name_message(group_name, vpc_id.as_deref(), *matches)
+    ))]
+    SecurityGroupNameError {
+        group_name: String,
+        vpc_id: Option<String>,
+        matches: usize,
+    },
+
+    #[snafu(display("Error with snapshots of {}: {}", instance_name, message))]
+    SnapshotError {
+        instance_name: String,
+        message: String,
+    },
+
+    #[snafu(display("Error with volume {}: {}", volume_id, message))]
+    VolumeError { volume_id: String, message: String },
+
+    #[snafu(display("Error with image {}: {}", image_id, message))]
+    ImageError { image_id: String, message: String },
+
+    #[snafu(display("Image {} not found", image_id))]
+    ImageNotFoundError { image_id: String },
+
+    #[snafu(display("Error changing protection on instance {}: {}", instance_name, message))]
+    ProtectInstanceError {
+        instance_name: String,
+        message: String,
+    },
+
+    #[snafu(display("Error in config file {}: {}", config_location(path, line), message))]
+    ConfigError {
+        path: String,
+        line: Option<usize>,
+        message: String,
+    },
+
     #[snafu(display("Profile named {} not found", profile_name))]
     ProfileNotFoundError { profile_name: String },
 
     #[snafu(display("Instance named {} not found", instance_name))]
     InstanceNotFoundError { instance_name: String },
 
+    #[snafu(display("Template named {} not found", template_name))]
+    TemplateNotFoundError { template_name: String },
+
     #[snafu(display("Public IP address not found for {} - is it stopped?", instance_name))]
     IPAddressNotFoundError { instance_name: String },
 
+    #[snafu(display("Error getting public IP address from {}: {}", url, message))]
+    PublicIpError { url: String, message: String },
+
+    #[snafu(display("SSH to {} failed: {}", instance_name, message))]
+    SshError {
+        instance_name: String,
+        message: String,
+    },
+
+    #[snafu(display("Remote command on {} exited with status {}", instance_name, status))]
+    RemoteCommandError { instance_name: String, status: i32 },
+
     #[snafu(display("Error parsing date: {}", error))]
     DateParseError { error: chrono::ParseError },
 
@@ -88,32 +168,181 @@ pub enum AwsInstanceError {
     #[snafu(display("Error parsing JSON: {}", error))]
     JSONParseError { error: serde_json::Error },
 
+    #[snafu(display("I/O error: {}", error))]
+    IOError { error: std::io::Error },
+
     #[snafu(display("Blocking error"))]
     Blocking,
 }
 
+/// Broad classes of error, each with its own exit code so wrapper scripts can react
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+    Other,
+    Validation,
+    NotFound,
+    Auth,
+    Throttled,
+    Service,
+    Ssh,
+    Network,
+}
+
+impl ErrorKind {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorKind::Other => 1,
+            ErrorKind::Validation => 2,
+            ErrorKind::NotFound => 3,
+            ErrorKind::Auth => 4,
+            ErrorKind::Throttled => 5,
+            ErrorKind::Service => 6,
+            ErrorKind::Ssh => 7,
+            ErrorKind::Network => 8,
+        }
+    }
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                ErrorKind::Other => "other",
+                ErrorKind::Validation => "validation",
+                ErrorKind::NotFound => "not-found",
+                ErrorKind::Auth => "auth",
+                ErrorKind::Throttled => "throttled",
+                ErrorKind::Service => "service",
+                ErrorKind::Ssh => "ssh",
+                ErrorKind::Network => "network",
+            }
+        )
+    }
+}
+
+fn kind_for_code(code: &str) -> ErrorKind {
+    match code {
+        code if THROTTLING_CODES.contains(&code) => ErrorKind::Throttled,
+        code if code.ends_with(".NotFound") => ErrorKind::NotFound,
+        "AuthFailure"
+        | "UnauthorizedOperation"
+        | "AccessDenied"
+        | "InvalidClientTokenId"
+        | "ExpiredToken"
+        | "SignatureDoesNotMatch" => ErrorKind::Auth,
+        code if code.ends_with(".Malformed")
+            || code.starts_with("InvalidParameter")
+            || code == "MissingParameter" =>
+        {
+            ErrorKind::Validation
+        }
+        _ => ErrorKind::Service,
+    }
+}
+
+impl AwsInstanceError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            AwsInstanceError::ParseRegionError { .. }
+            | AwsInstanceError::ConfigError { .. }
+            | AwsInstanceError::Validation { .. }
+            | AwsInstanceError::RegexParseError { .. } => ErrorKind::Validation,
+            AwsInstanceError::ProfileNotFoundError { .. }
+            | AwsInstanceError::InstanceNotFoundError { .. }
+            | AwsInstanceError::TemplateNotFoundError { .. }
+            | AwsInstanceError::ImageNotFoundError { .. }
+            | AwsInstanceError::SecurityGroupNameError { matches: 0, .. }
+            | AwsInstanceError::IPAddressNotFoundError { .. } => ErrorKind::NotFound,
+            // More than one group with the name
+            AwsInstanceError::SecurityGroupNameError { .. } => ErrorKind::Validation,
+            AwsInstanceError::Credentials { .. } | AwsInstanceError::Unauthorized { .. } => {
+                ErrorKind::Auth
+            }
+            AwsInstanceError::Unknown { errors, .. } => errors
+                .first()
+                .and_then(|error| error.code.as_deref())
+                .map(kind_for_code)
+                .unwrap_or(ErrorKind::Service),
+            AwsInstanceError::HttpDispatch { .. }
+            | AwsInstanceError::Service { .. }
+            | AwsInstanceError::RusotoParseError { .. }
+            | AwsInstanceError::UnexpectedResponse { .. }
+            | AwsInstanceError::DescribeInstancesError { .. }
+            | AwsInstanceError::DescribeImagesError { .. } => ErrorKind::Service,
+            AwsInstanceError::SshError { .. } | AwsInstanceError::RemoteCommandError { .. } => {
+                ErrorKind::Ssh
+            }
+            AwsInstanceError::PublicIpError { .. } => ErrorKind::Network,
+            _ => ErrorKind::Other,
+        }
+    }
+
+    /// The process exit code for this error. A remote command's status is passed through
+    /// like ssh itself does, and always takes precedence, even where it overlaps one of ours.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AwsInstanceError::RemoteCommandError { status, .. } => *status,
+            _ => self.kind().exit_code(),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let (code, request_id) = match self {
+            AwsInstanceError::Unknown { errors, request_id } => (
+                errors.first().and_then(|error| error.code.clone()),
+                request_id.clone(),
+            ),
+            AwsInstanceError::Unauthorized { request_id, .. } => {
+                (Some("UnauthorizedOperation".into()), request_id.clone())
+            }
+            _ => (None, None),
+        };
+
+        json!({
+            "kind": self.kind().to_string(),
+            "message": self.to_string(),
+            "code": code,
+            "request_id": request_id,
+            "exit_code": self.exit_code(),
+        })
+    }
+}
+
+// EC2 wraps its errors in <Response><Errors><Error>, while the query-protocol services
+// (STS, IAM) use <ErrorResponse><Error>, and they disagree on the case of RequestID
 #[derive(Debug, Deserialize)]
 struct AwsXmlResponse {
-    #[serde(rename = "Errors")]
-    errors: Vec<AwsXmlErrors>,
+    #[serde(rename = "Errors", default)]
+    errors: Option<AwsXmlErrors>,
 
-    #[serde(rename = "RequestID")]
-    request_id: String,
+    #[serde(rename = "Error", default)]
+    error: Option<AwsXmlError>,
+
+    #[serde(rename = "RequestID", alias = "RequestId", default)]
+    request_id: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 struct AwsXmlErrors {
-    #[serde(rename = "Error")]
-    error: AwsXmlError,
+    #[serde(rename = "Error", default)]
+    errors: Vec<AwsXmlError>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct AwsXmlError {
     #[serde(rename = "Code")]
-    code: Option<String>,
+    pub code: Option<String>,
 
     #[serde(rename = "Message")]
-    message: Option<String>,
+    pub message: Option<String>,
+}
+
+impl AwsXmlError {
+    pub fn hint(&self) -> Option<&'static str> {
+        self.code.as_deref().and_then(hint_for_code)
+    }
 }
 
 impl Display for AwsXmlError {
@@ -123,7 +352,119 @@ impl Display for AwsXmlError {
             "{}\nError code: {}",
             self.message.clone().unwrap_or_else(|| "None".into()),
             self.code.clone().unwrap_or_else(|| "None".into())
-        )
+        )?;
+        if let Some(hint) = self.hint() {
+            write!(f, "\nHint: {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+fn hint_for_code(code: &str) -> Option<&'static str> {
+    match code {
+        "InvalidAMIID.NotFound" | "InvalidAMIID.Malformed" => Some(
+            "AMIs are specific to a region - check the AMI ID and region, or use 'aws-instance list-amis' to find one",
+        ),
+        "InsufficientInstanceCapacity" => Some(
+            "AWS doesn't currently have enough capacity for this instance type - try again later, or use a different instance type or region",
+        ),
+        "UnauthorizedOperation" => Some(
+            "Your credentials don't allow this action - check the IAM policy for this profile; 'aws sts decode-authorization-message' can decode any encoded message",
+        ),
+        "InvalidKeyPair.NotFound" => Some(
+            "Key pairs are specific to a region - check the keypair setting for this profile",
+        ),
+        "InvalidGroup.NotFound" => Some(
+            "Security groups are specific to a region and VPC - use 'aws-instance list-security-groups' to find one",
+        ),
+        "RequestLimitExceeded" => Some("AWS is throttling requests - wait a little and try again"),
+        _ => None,
+    }
+}
+
+fn security_group_name_message(group_name: &str, vpc_id: Option<&str>, matches: usize) -> String {
+    let location = match vpc_id {
+        Some(vpc_id) => format!(" in {}", vpc_id),
+        None => String::new(),
+    };
+    match matches {
+        0 => format!("No security group named {}{}", group_name, location),
+        _ => format!(
+            "{} security groups are named {}{}, use the group ID instead",
+            matches, group_name, location
+        ),
+    }
+}
+
+fn config_location(path: &str, line: &Option<usize>) -> String {
+    match line {
+        Some(line) => format!("{}, line {}", path, line),
+        None => path.to_string(),
+    }
+}
+
+fn format_errors(errors: &[AwsXmlError]) -> String {
+    if errors.is_empty() {
+        "No error details returned".into()
+    } else {
+        errors
+            .iter()
+            .map(|error| error.to_string())
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+impl From<BufferedHttpResponse> for AwsInstanceError {
+    fn from(response: BufferedHttpResponse) -> Self {
+        decode_error_response(response.status.as_u16(), response.body_as_str())
+    }
+}
+
+/// Turn the XML body of a failed AWS request into an error
+fn decode_error_response(status: u16, body: &str) -> AwsInstanceError {
+    let xml_response: AwsXmlResponse = match serde_xml_rs::de::from_str(body) {
+        Ok(xml_response) => xml_response,
+        Err(_) => {
+            return AwsInstanceError::UnexpectedResponse {
+                status,
+                body: if body.trim().is_empty() {
+                    "<empty body>".into()
+                } else {
+                    body.trim().into()
+                },
+            }
+        }
+    };
+
+    let mut errors = xml_response
+        .errors
+        .map(|errors| errors.errors)
+        .unwrap_or_default();
+    errors.extend(xml_response.error);
+    if errors.is_empty() {
+        return AwsInstanceError::UnexpectedResponse {
+            status,
+            body: body.trim().into(),
+        };
+    }
+
+    // AWS can return more than one error, so keep all their messages
+    let message = errors
+        .iter()
+        .filter_map(|error| error.message.clone())
+        .collect::<Vec<String>>()
+        .join("\n");
+    match errors[0].code.as_deref() {
+        Some("DryRunOperation") => AwsInstanceError::DryRunOperation { message },
+        Some("UnauthorizedOperation") => AwsInstanceError::Unauthorized {
+            message,
+            request_id: xml_response.request_id,
+        },
+        _ => AwsInstanceError::Unknown {
+            errors,
+            request_id: xml_response.request_id,
+        },
     }
 }
 
@@ -146,23 +487,18 @@ where
             RusotoError::Credentials(error) => AwsInstanceError::Credentials { source: error },
             RusotoError::ParseError(msg) => AwsInstanceError::RusotoParseError { message: msg },
             RusotoError::Validation(msg) => AwsInstanceError::Validation { message: msg },
-            RusotoError::Unknown(response) => {
-                let xml_response: AwsXmlResponse =
-                    serde_xml_rs::de::from_str(response.body_as_str()).unwrap();
-                AwsInstanceError::Unknown {
-                    errors: xml_response
-                        .errors
-                        .iter()
-                        .map(|e| e.error.clone())
-                        .collect(),
-                    request_id: xml_response.request_id,
-                }
-            }
+            RusotoError::Unknown(response) => response.into(),
             RusotoError::Blocking => AwsInstanceError::Blocking,
         }
     }
 }
 
+impl From<CredentialsError> for AwsInstanceError {
+    fn from(e: CredentialsError) -> Self {
+        AwsInstanceError::Credentials { source: e }
+    }
+}
+
 impl From<rusoto_ec2::DescribeInstancesError> for AwsInstanceError {
     fn from(e: rusoto_ec2::DescribeInstancesError) -> Self {
         AwsInstanceError::DescribeInstancesError { source: e }
@@ -192,3 +528,108 @@ impl From<serde_json::Error> for AwsInstanceError {
         AwsInstanceError::JSONParseError { error: e }
     }
 }
+
+impl From<std::io::Error> for AwsInstanceError {
+    fn from(e: std::io::Error) -> Self {
+        AwsInstanceError::IOError { error: e }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_ec2_error() {
+        let body = "<Response><Errors><Error><Code>InvalidAMIID.NotFound</Code>\
+            <Message>The image id '[ami-123]' does not exist</Message></Error></Errors>\
+            <RequestID>abc-123</RequestID></Response>";
+        let error = decode_error_response(400, body);
+
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+        let message = error.to_string();
+        assert!(message.contains("abc-123"));
+        assert!(message.contains("The image id '[ami-123]' does not exist"));
+        assert!(message.contains("Hint: AMIs are specific to a region"));
+        assert_eq!(error.to_json()["code"], "InvalidAMIID.NotFound");
+    }
+
+    #[test]
+    fn keeps_every_error() {
+        let body = "<Response><Errors>\
+            <Error><Code>InvalidParameterValue</Code><Message>First problem</Message></Error>\
+            <Error><Code>InvalidParameterValue</Code><Message>Second problem</Message></Error>\
+            </Errors><RequestID>abc-123</RequestID></Response>";
+        let message = decode_error_response(400, body).to_string();
+
+        assert!(message.contains("First problem"));
+        assert!(message.contains("Second problem"));
+    }
+
+    #[test]
+    fn decodes_unauthorized_with_request_id() {
+        let body = "<Response><Errors>\
+            <Error><Code>UnauthorizedOperation</Code>\
+            <Message>You are not authorized</Message></Error>\
+            <Error><Code>UnauthorizedOperation</Code>\
+            <Message>Encoded message: xyz</Message></Error>\
+            </Errors><RequestID>def-456</RequestID></Response>";
+        let error = decode_error_response(403, body);
+
+        assert!(matches!(error, AwsInstanceError::Unauthorized { .. }));
+        assert_eq!(error.kind(), ErrorKind::Auth);
+        let message = error.to_string();
+        assert!(message.contains("request ID def-456"));
+        assert!(message.contains("You are not authorized"));
+        assert!(message.contains("Encoded message: xyz"));
+        assert_eq!(error.to_json()["request_id"], "def-456");
+    }
+
+    #[test]
+    fn decodes_query_protocol_error() {
+        let body = "<ErrorResponse><Error><Type>Sender</Type><Code>ExpiredToken</Code>\
+            <Message>The security token included in the request is expired</Message></Error>\
+            <RequestId>ghi-789</RequestId></ErrorResponse>";
+        let error = decode_error_response(403, body);
+
+        assert_eq!(error.kind(), ErrorKind::Auth);
+        assert!(error.to_string().contains("ghi-789"));
+    }
+
+    #[test]
+    fn decodes_dry_run() {
+        let body = "<Response><Errors><Error><Code>DryRunOperation</Code>\
+            <Message>Request would have succeeded, but DryRun flag is set.</Message></Error>\
+            </Errors><RequestID>r</RequestID></Response>";
+
+        assert!(matches!(
+            decode_error_response(412, body),
+            AwsInstanceError::DryRunOperation { .. }
+        ));
+    }
+
+    #[test]
+    fn keeps_body_that_is_not_xml() {
+        match decode_error_response(502, "Bad Gateway") {
+            AwsInstanceError::UnexpectedResponse { status, body } => {
+                assert_eq!(status, 502);
+                assert_eq!(body, "Bad Gateway");
+            }
+            error => panic!("unexpected error {:?}", error),
+        }
+        assert!(decode_error_response(500, "  ")
+            .to_string()
+            .contains("<empty body>"));
+    }
+
+    #[test]
+    fn remote_status_takes_precedence() {
+        let error = AwsInstanceError::RemoteCommandError {
+            instance_name: "foo".into(),
+            status: 3,
+        };
+        assert_eq!(error.exit_code(), 3);
+        assert_eq!(error.kind(), ErrorKind::Ssh);
+        assert_eq!(error.to_json()["exit_code"], 3);
+    }
+}
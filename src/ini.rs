@@ -0,0 +1,558 @@
+use std::fs;
+use std::path::Path;
+
+use crate::{AwsInstanceError, Result};
+
+#[derive(Clone, Debug)]
+pub struct IniEntry {
+    pub key: String,
+    pub value: String,
+    pub line: usize,
+    pub last_line: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct IniSection {
+    pub name: String,
+    pub line: usize,
+    pub entries: Vec<IniEntry>,
+}
+
+/// Read and parse an INI file
+pub fn parse_ini_file(path: &Path) -> Result<Vec<IniSection>> {
+    let contents = fs::read_to_string(path).map_err(|error| AwsInstanceError::ConfigError {
+        path: path.display().to_string(),
+        line: None,
+        message: format!("Error reading file: {}", error),
+    })?;
+
+    parse_ini(&contents, path)
+}
+
+/// Parse INI-formatted text. This understands:
+///
+/// - `#` and `;` comments, on their own line or after an unquoted value
+/// - single- and double-quoted values (double quotes allow `\"`, `\\`, `\n` and `\t` escapes)
+/// - continuation lines, either with a trailing `\`, or indented lines following a key,
+///   as used for nested settings in the AWS config file
+pub fn parse_ini(contents: &str, path: &Path) -> Result<Vec<IniSection>> {
+    let error = |line: usize, message: String| AwsInstanceError::ConfigError {
+        path: path.display().to_string(),
+        line: Some(line),
+        message,
+    };
+
+    let mut sections: Vec<IniSection> = vec![];
+    let mut lines = contents.lines().enumerate();
+    // Whether the last key started a block of nested settings
+    let mut nested = false;
+
+    while let Some((index, raw_line)) = lines.next() {
+        let line_number = index + 1;
+        let mut last_line = line_number;
+        let mut line = raw_line.to_string();
+
+        // Join lines ending in a backslash
+        while line.trim_end().ends_with('\\') && !is_comment(&line) {
+            let trimmed = line.trim_end();
+            line = trimmed[..trimmed.len() - 1].trim_end().to_string();
+            match lines.next() {
+                Some((next_index, next)) => {
+                    last_line = next_index + 1;
+                    line.push(' ');
+                    line.push_str(next.trim_start());
+                }
+                None => break,
+            }
+        }
+
+        let trimmed = line.trim();
+        if trimmed.is_empty() || is_comment(trimmed) {
+            continue;
+        }
+
+        if trimmed.starts_with('[') {
+            let end = trimmed
+                .find(']')
+                .ok_or_else(|| error(line_number, "Missing ']' in section header".into()))?;
+            let rest = trimmed[end + 1..].trim();
+            if !rest.is_empty() && !is_comment(rest) {
+                return Err(error(
+                    line_number,
+                    format!("Unexpected text after section header: '{}'", rest),
+                ));
+            }
+            let name = trimmed[1..end].trim();
+            if name.is_empty() {
+                return Err(error(line_number, "Empty section name".into()));
+            }
+            nested = false;
+            sections.push(IniSection {
+                name: name.to_string(),
+                line: line_number,
+                entries: vec![],
+            });
+            continue;
+        }
+
+        let section = sections.last_mut().ok_or_else(|| {
+            error(
+                line_number,
+                "Setting found before any [section] header".into(),
+            )
+        })?;
+
+        // Indented lines continue the previous value if they have no '=', or if the
+        // previous key had an empty value (nested settings)
+        let indented = line.starts_with(char::is_whitespace);
+        if let Some(previous) = section.entries.last_mut() {
+            if indented && (!trimmed.contains('=') || previous.value.is_empty() || nested) {
+                if previous.value.is_empty() {
+                    nested = true;
+                } else {
+                    previous.value.push(' ');
+                }
+                previous
+                    .value
+                    .push_str(&parse_value(trimmed).map_err(|m| error(line_number, m))?);
+                previous.last_line = last_line;
+                continue;
+            }
+        }
+
+        let (key, value) = trimmed.split_once('=').ok_or_else(|| {
+            error(
+                line_number,
+                format!("Expected 'key = value', found '{}'", trimmed),
+            )
+        })?;
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(error(line_number, "Missing key before '='".into()));
+        }
+
+        nested = false;
+        section.entries.push(IniEntry {
+            key: key.to_string(),
+            value: parse_value(value.trim()).map_err(|m| error(line_number, m))?,
+            line: line_number,
+            last_line,
+        });
+    }
+
+    Ok(sections)
+}
+
+/// Set (or, if `value` is `None`, remove) a key in INI-formatted text, leaving everything
+/// else, including comments, as it was. The section is added if it doesn't exist.
+pub fn update_ini(
+    contents: &str,
+    path: &Path,
+    section_name: &str,
+    key: &str,
+    value: Option<&str>,
+) -> Result<String> {
+    let sections = parse_ini(contents, path)?;
+    let mut lines: Vec<Option<String>> = contents.lines().map(|l| Some(l.to_string())).collect();
+    let new_line = value.map(|value| format!("{} = {}", key, quote_value(value)));
+
+    let matching: Vec<&IniSection> = sections.iter().filter(|s| s.name == section_name).collect();
+    let existing: Vec<&IniEntry> = matching
+        .iter()
+        .flat_map(|section| section.entries.iter())
+        .filter(|entry| entry.key == key)
+        .collect();
+
+    // Lines are numbered from 1; None marks a line to be dropped
+    let mut insert_after: Option<(usize, String)> = None;
+    match (existing.last(), new_line) {
+        (Some(last), Some(new_line)) => {
+            for entry in existing.iter().filter(|e| e.line != last.line) {
+                (entry.line..=entry.last_line).for_each(|n| lines[n - 1] = None);
+            }
+            (last.line + 1..=last.last_line).for_each(|n| lines[n - 1] = None);
+            lines[last.line - 1] = Some(new_line);
+        }
+        (Some(_), None) => {
+            for entry in existing.iter() {
+                (entry.line..=entry.last_line).for_each(|n| lines[n - 1] = None);
+            }
+        }
+        (None, Some(new_line)) => match matching.last() {
+            Some(section) => {
+                let after = section
+                    .entries
+                    .last()
+                    .map(|entry| entry.last_line)
+                    .unwrap_or(section.line);
+                insert_after = Some((after, new_line));
+            }
+            None => {
+                if lines.iter().flatten().any(|l| !l.trim().is_empty()) {
+                    lines.push(Some(String::new()));
+                }
+                lines.push(Some(format!("[{}]", section_name)));
+                lines.push(Some(new_line));
+            }
+        },
+        (None, None) => (),
+    }
+
+    let mut output = String::new();
+    for (index, line) in lines.into_iter().enumerate() {
+        if let Some(line) = line {
+            output.push_str(&line);
+            output.push('\n');
+        }
+        if let Some((after, ref new_line)) = insert_after {
+            if after == index + 1 {
+                output.push_str(new_line);
+                output.push('\n');
+            }
+        }
+    }
+
+    Ok(output)
+}
+
+/// Quote a value if it wouldn't survive being parsed back unquoted
+fn quote_value(value: &str) -> String {
+    let needs_quotes = value.is_empty()
+        || value.trim() != value
+        || value.starts_with('"')
+        || value.starts_with('\'')
+        || value.ends_with('\\')
+        || value.contains(" #")
+        || value.contains(" ;")
+        || value.contains('\n')
+        || value.contains('\t');
+
+    if needs_quotes {
+        format!(
+            "\"{}\"",
+            value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n")
+                .replace('\t', "\\t")
+        )
+    } else {
+        value.to_string()
+    }
+}
+
+/// Split a list value on commas. Items can contain spaces, e.g. `Team=Data Science`.
+pub fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect()
+}
+
+fn is_comment(line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with('#') || line.starts_with(';')
+}
+
+fn parse_value(value: &str) -> std::result::Result<String, String> {
+    let mut chars = value.chars();
+    let (parsed, rest) = match chars.next() {
+        Some('"') => {
+            let mut parsed = String::new();
+            loop {
+                match chars.next() {
+                    None => return Err("Unterminated double-quoted value".into()),
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => parsed.push('\n'),
+                        Some('t') => parsed.push('\t'),
+                        Some(c @ ('"' | '\\')) => parsed.push(c),
+                        Some(c) => {
+                            parsed.push('\\');
+                            parsed.push(c);
+                        }
+                        None => return Err("Unterminated double-quoted value".into()),
+                    },
+                    Some(c) => parsed.push(c),
+                }
+            }
+            (parsed, chars.as_str())
+        }
+        Some('\'') => {
+            let rest = chars.as_str();
+            let end = rest
+                .find('\'')
+                .ok_or_else(|| "Unterminated single-quoted value".to_string())?;
+            (rest[..end].to_string(), &rest[end + 1..])
+        }
+        _ => {
+            // Strip any inline comment, which has to be preceded by whitespace
+            let end = value
+                .char_indices()
+                .find(|&(i, c)| (c == '#' || c == ';') && value[..i].ends_with(char::is_whitespace))
+                .map(|(i, _)| i)
+                .unwrap_or(value.len());
+            return Ok(value[..end].trim().to_string());
+        }
+    };
+
+    let rest = rest.trim();
+    if !rest.is_empty() && !is_comment(rest) {
+        return Err(format!("Unexpected text after quoted value: '{}'", rest));
+    }
+
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> Result<Vec<IniSection>> {
+        parse_ini(contents, Path::new("config"))
+    }
+
+    fn values(contents: &str) -> Vec<(String, String)> {
+        parse(contents)
+            .unwrap()
+            .into_iter()
+            .flat_map(|section| section.entries)
+            .map(|entry| (entry.key, entry.value))
+            .collect()
+    }
+
+    fn error_line(contents: &str) -> Option<usize> {
+        match parse(contents) {
+            Err(AwsInstanceError::ConfigError { line, .. }) => line,
+            result => panic!("expected a config error, got {:?}", result),
+        }
+    }
+
+    fn pair(key: &str, value: &str) -> (String, String) {
+        (key.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn sections_and_values() {
+        let sections =
+            parse("[default]\nregion = us-east-1\n\n[profile work]\nkeypair=work\n").unwrap();
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections[0].name, "default");
+        assert_eq!(sections[1].name, "profile work");
+        assert_eq!(sections[1].line, 4);
+        assert_eq!(sections[1].entries[0].key, "keypair");
+        assert_eq!(sections[1].entries[0].value, "work");
+        assert_eq!(sections[1].entries[0].line, 5);
+    }
+
+    #[test]
+    fn comments() {
+        assert_eq!(
+            values(concat!(
+                "# comment\n",
+                "; comment\n",
+                "[default] # comment\n",
+                "a = b # comment\n",
+                "c = d;e\n",
+                "f = g ; comment\n",
+            )),
+            vec![pair("a", "b"), pair("c", "d;e"), pair("f", "g")]
+        );
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(
+            values(concat!(
+                "[default]\n",
+                "a = \"x # not a comment\" # comment\n",
+                "b = 'single \\n quoted'\n",
+                "c = \"say \\\"hi\\\"\\tback\\\\\"\n",
+                "d = \"\"\n",
+                "e = \"Team=Data Science\"\n",
+            )),
+            vec![
+                pair("a", "x # not a comment"),
+                pair("b", "single \\n quoted"),
+                pair("c", "say \"hi\"\tback\\"),
+                pair("d", ""),
+                pair("e", "Team=Data Science"),
+            ]
+        );
+    }
+
+    #[test]
+    fn backslash_continuation() {
+        let sections =
+            parse("[default]\nsecurity-groups = sg-1, \\\n    sg-2\nregion = us-east-1\n").unwrap();
+        let entry = &sections[0].entries[0];
+        assert_eq!(entry.value, "sg-1, sg-2");
+        assert_eq!((entry.line, entry.last_line), (2, 3));
+        assert_eq!(sections[0].entries[1].key, "region");
+    }
+
+    #[test]
+    fn indented_continuation() {
+        let sections = parse(concat!(
+            "[default]\n",
+            "s3 =\n",
+            "    max_concurrent_requests = 20\n",
+            "    addressing_style = path\n",
+            "region = us-east-1\n",
+            "security-groups = sg-1,\n",
+            "  sg-2\n",
+        ))
+        .unwrap();
+        let entries = &sections[0].entries;
+        assert_eq!(entries[0].key, "s3");
+        assert_eq!(
+            entries[0].value,
+            "max_concurrent_requests = 20 addressing_style = path"
+        );
+        assert_eq!((entries[0].line, entries[0].last_line), (2, 4));
+        assert_eq!(entries[1].value, "us-east-1");
+        assert_eq!(entries[2].value, "sg-1, sg-2");
+    }
+
+    #[test]
+    fn errors_which_used_to_be_ignored() {
+        assert_eq!(error_line("region = us-east-1\n"), Some(1));
+        assert_eq!(error_line("[default]\nnot a setting\n"), Some(2));
+        assert_eq!(error_line("[default\nregion = us-east-1\n"), Some(1));
+        assert_eq!(error_line("[default] region = us-east-1\n"), Some(1));
+        assert_eq!(error_line("[]\n"), Some(1));
+        assert_eq!(error_line("[default]\n= value\n"), Some(2));
+        assert_eq!(error_line("[default]\na = \"unterminated\n"), Some(2));
+        assert_eq!(error_line("[default]\na = 'unterminated\n"), Some(2));
+        assert_eq!(error_line("[default]\n\na = \"x\" y\n"), Some(3));
+    }
+
+    #[test]
+    fn quote_value_round_trips() {
+        for value in [
+            "plain",
+            "with spaces",
+            "",
+            " leading",
+            "trailing ",
+            "x # y",
+            "x ; y",
+            "\"quoted\"",
+            "'single'",
+            "back\\slash\\",
+            "tab\there",
+            "new\nline",
+        ] {
+            let contents = format!("[default]\nkey = {}\n", quote_value(value));
+            assert_eq!(values(&contents), vec![pair("key", value)], "{}", contents);
+        }
+    }
+
+    #[test]
+    fn update_replaces_value() {
+        let contents = "# settings\n[default]\nregion = us-east-1 # home\nkeypair = old\n";
+        let updated = update_ini(
+            contents,
+            Path::new("config"),
+            "default",
+            "keypair",
+            Some("new key"),
+        )
+        .unwrap();
+        assert_eq!(
+            updated,
+            "# settings\n[default]\nregion = us-east-1 # home\nkeypair = new key\n"
+        );
+        assert_eq!(
+            values(&updated),
+            vec![pair("region", "us-east-1"), pair("keypair", "new key")]
+        );
+    }
+
+    #[test]
+    fn update_replaces_continued_and_duplicate_values() {
+        let contents = concat!(
+            "[default]\n",
+            "security-groups = sg-1, \\\n",
+            "  sg-2\n",
+            "region = us-east-1\n",
+            "security-groups = sg-3\n",
+        );
+        let updated = update_ini(
+            contents,
+            Path::new("config"),
+            "default",
+            "security-groups",
+            Some("sg-4"),
+        )
+        .unwrap();
+        assert_eq!(
+            updated,
+            "[default]\nregion = us-east-1\nsecurity-groups = sg-4\n"
+        );
+    }
+
+    #[test]
+    fn update_adds_to_existing_section() {
+        let contents = "[default]\nregion = us-east-1\n\n[work]\nkeypair = work\n";
+        let updated = update_ini(
+            contents,
+            Path::new("config"),
+            "default",
+            "key",
+            Some("a # b"),
+        )
+        .unwrap();
+        assert_eq!(
+            updated,
+            "[default]\nregion = us-east-1\nkey = \"a # b\"\n\n[work]\nkeypair = work\n"
+        );
+        assert_eq!(parse(&updated).unwrap()[0].entries[1].value, "a # b");
+    }
+
+    #[test]
+    fn update_adds_section() {
+        let updated = update_ini(
+            "[default]\nregion = us-east-1\n",
+            Path::new("config"),
+            "work",
+            "keypair",
+            Some("work"),
+        )
+        .unwrap();
+        assert_eq!(
+            updated,
+            "[default]\nregion = us-east-1\n\n[work]\nkeypair = work\n"
+        );
+        let updated = update_ini("", Path::new("config"), "work", "keypair", Some("work")).unwrap();
+        assert_eq!(updated, "[work]\nkeypair = work\n");
+    }
+
+    #[test]
+    fn update_removes_value() {
+        let contents =
+            "[default]\nregion = us-east-1\nkeypair = a \\\n  b\n[work]\nkeypair = work\n";
+        let updated =
+            update_ini(contents, Path::new("config"), "default", "keypair", None).unwrap();
+        assert_eq!(
+            updated,
+            "[default]\nregion = us-east-1\n[work]\nkeypair = work\n"
+        );
+    }
+
+    #[test]
+    fn update_rejects_unparseable_file() {
+        assert!(update_ini("[default\n", Path::new("config"), "default", "a", Some("b")).is_err());
+    }
+
+    #[test]
+    fn lists_split_on_commas() {
+        assert_eq!(
+            split_list("Team=Data Science, env=dev,,  x "),
+            vec!["Team=Data Science", "env=dev", "x"]
+        );
+        assert!(split_list("").is_empty());
+    }
+}
@@ -1,16 +1,24 @@
+mod ami_cache;
 mod cmdline;
 mod commands;
+mod credentials;
 mod error;
+mod ini;
 mod profile;
+mod retry;
+mod template;
 mod util;
 
 use rusoto_core::{HttpClient, Region};
-use rusoto_credential::{DefaultCredentialsProvider, ProfileProvider};
+use rusoto_credential::{DefaultCredentialsProvider, ProfileProvider, ProvideAwsCredentials};
 use rusoto_ec2::Ec2Client;
-use std::str::FromStr;
+use std::env;
+use std::sync::Arc;
 
-use crate::cmdline::parse_command_line;
-use crate::profile::{get_profile, ConfigFileReader, Profile};
+use crate::cmdline::{parse_command_line, CmdLineOptions, ErrorFormat, SubCommands};
+use crate::credentials::get_credentials_provider;
+use crate::profile::{get_profile, resolve_region, ConfigFileReader, Profile};
+use crate::retry::{RetryingHttpClient, DEFAULT_MAX_RETRIES};
 
 pub use crate::error::{AwsInstanceError, Result};
 pub use crate::util::print_state_changes;
@@ -19,39 +27,158 @@ pub use crate::util::print_state_changes;
 async fn main() {
     env_logger::init();
 
-    if let Err(error) = run_commands().await {
-        eprintln!("{}", error);
-        std::process::exit(1);
+    let options = parse_command_line();
+    let error_format = options.error_format;
+
+    if let Err(error) = run_commands(options).await {
+        match error_format {
+            ErrorFormat::Text => eprintln!("{}", error),
+            ErrorFormat::Json => eprintln!("{}", error.to_json()),
+        }
+        std::process::exit(error.exit_code());
     }
 }
 
-fn get_ec2_client(region: Region, profile: &str) -> Ec2Client {
-    let mut profile_provider = ProfileProvider::new().expect("Error creating profile provider");
-    if !profile.is_empty() {
-        profile_provider.set_profile(profile);
-        Ec2Client::new_with(HttpClient::new().unwrap(), profile_provider, region)
+async fn get_ec2_client(
+    config_file: &ConfigFileReader,
+    profile_name: &str,
+    explicit_profile: bool,
+    profile: &Profile,
+    region: Region,
+    max_retries: u32,
+) -> Result<Ec2Client> {
+    // Assumed roles and SSO need resolving up front, since they might prompt for an MFA code
+    if let Some(provider) = get_credentials_provider(
+        config_file,
+        profile_name,
+        &region,
+        profile.sts_endpoint.as_deref(),
+    )
+    .await?
+    {
+        return Ok(retrying_client(provider, region, max_retries));
+    }
+
+    // Without a profile on the command line, use the whole default chain, so that credentials
+    // in the environment work even if there are no AWS config files at all
+    if explicit_profile {
+        let mut profile_provider = ProfileProvider::new().expect("Error creating profile provider");
+        profile_provider.set_profile(profile_name);
+        Ok(retrying_client(profile_provider, region, max_retries))
     } else {
-        Ec2Client::new_with(
-            HttpClient::new().unwrap(),
+        Ok(retrying_client(
             DefaultCredentialsProvider::new().unwrap(),
             region,
-        )
+            max_retries,
+        ))
     }
 }
 
-async fn run_commands() -> Result<()> {
-    let options = parse_command_line();
+// The HTTP client shares the credentials provider, so it can sign each retry again
+fn retrying_client<P>(credentials_provider: P, region: Region, max_retries: u32) -> Ec2Client
+where
+    P: ProvideAwsCredentials + Send + Sync + 'static,
+{
+    let credentials_provider = Arc::new(credentials_provider);
+    let http_client = RetryingHttpClient::new(
+        HttpClient::new().unwrap(),
+        credentials_provider.clone(),
+        max_retries,
+    );
+    Ec2Client::new_with(http_client, credentials_provider, region)
+}
 
-    let config_file = ConfigFileReader::new(options.config_file);
-    let profile_name = options.profile.or_else(|| Some("default".into())).unwrap();
-    let profile = get_profile(&profile_name, &config_file)?;
-    let region = match options.region {
-        Some(region_name) => Region::from_str(&region_name)?,
-        None => profile.region.clone(),
-    };
+async fn run_commands(options: CmdLineOptions) -> Result<()> {
+    let config_file = ConfigFileReader::new(options.config_file)?;
+    // Settings come from the same profile as the credentials, which rusoto's default chain
+    // takes from AWS_PROFILE when there's no --profile
+    let profile_name = options
+        .profile
+        .clone()
+        .or_else(|| env::var("AWS_PROFILE").ok().filter(|name| !name.is_empty()))
+        .unwrap_or_else(|| "default".into());
 
-    let ec2_client = get_ec2_client(region, &profile_name);
-    options.subcommand.run(&ec2_client, profile).await?;
+    // Most of the config commands need to work for profiles which don't exist yet
+    if let SubCommands::Config { ref command } = options.subcommand {
+        if !command.needs_client() {
+            return command.run(&config_file, &profile_name, None).await;
+        }
+    }
+
+    // A profile named with --profile has to exist. The default one needn't, since AWS may be
+    // configured entirely through the environment.
+    if options.profile.is_some() && !config_file.has_profile(&profile_name) {
+        return Err(AwsInstanceError::ProfileNotFoundError { profile_name });
+    }
+
+    let mut profile = get_profile(&profile_name, &config_file)?;
+    let region = resolve_region(
+        options.region.as_deref(),
+        options.endpoint_url.as_deref(),
+        &profile,
+        &profile_name,
+    )?;
+    profile.region = Some(region.clone());
+
+    let max_retries = options
+        .max_retries
+        .or(profile.max_retries)
+        .unwrap_or(DEFAULT_MAX_RETRIES);
+
+    let ec2_client = get_ec2_client(
+        &config_file,
+        &profile_name,
+        options.profile.is_some(),
+        &profile,
+        region.clone(),
+        max_retries,
+    )
+    .await?;
+    match options.subcommand {
+        SubCommands::Config { ref command } => {
+            command
+                .run(&config_file, &profile_name, Some((&ec2_client, &profile)))
+                .await?;
+        }
+        SubCommands::Image { ref command } => {
+            let destination = match command.destination_region() {
+                Some(region_name) => {
+                    let destination_region = resolve_region(
+                        Some(region_name),
+                        options.endpoint_url.as_deref(),
+                        &profile,
+                        &profile_name,
+                    )?;
+                    let destination_client = get_ec2_client(
+                        &config_file,
+                        &profile_name,
+                        options.profile.is_some(),
+                        &profile,
+                        destination_region.clone(),
+                        max_retries,
+                    )
+                    .await?;
+                    Some((destination_client, destination_region))
+                }
+                None => None,
+            };
+            command
+                .run(
+                    &ec2_client,
+                    &region,
+                    destination
+                        .as_ref()
+                        .map(|(client, region)| (client, region)),
+                    options.dry_run,
+                )
+                .await?;
+        }
+        SubCommands::Ec2(ref command) => {
+            command
+                .run(&ec2_client, &config_file, profile, options.dry_run)
+                .await?;
+        }
+    }
 
     Ok(())
 }
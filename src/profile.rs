@@ -1,63 +1,206 @@
-use lazy_static::lazy_static;
-use regex::Regex;
+use log::warn;
 use rusoto_core::Region;
 use std::collections::BTreeMap;
 use std::env;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+use crate::ini::{parse_ini_file, split_list};
 use crate::{AwsInstanceError, Result};
 
-type ConfigMap = BTreeMap<String, Profile>;
+/// The settings we understand in a profile
+pub const PROFILE_KEYS: &[&str] = &[
+    "region",
+    "keypair",
+    "key",
+    "instance-type",
+    "security-groups",
+    "protect-tag",
+    "max-retries",
+    "sts-endpoint",
+    "endpoint-url",
+    "ami-cache-ttl",
+    "ip-echo-url",
+];
 
-lazy_static! {
-    static ref SECTION_REGEX: Regex = Regex::new(r"^\[([^\]]*)\].*$").unwrap();
-    static ref AWS_CONFIG_SECTION_REGEX: Regex = Regex::new(r"^\[(?:profile )?(.*)\].*$").unwrap();
-    static ref VALUE_REGEX: Regex = Regex::new(r"^\s*(\S*)\s*=\s*(\S*).*$").unwrap();
+// Sections in our config file with this prefix are instance templates rather than profiles
+const TEMPLATE_PREFIX: &str = "template.";
+
+// Keys in our config file which name a profile to inherit settings from
+const INHERIT_KEYS: &[&str] = &["inherits", "source_profile"];
+
+// Settings the AWS config file spells with an underscore, and the name we use for them
+const KEY_ALIASES: &[(&str, &str)] = &[("endpoint_url", "endpoint-url")];
+
+fn canonical_key(key: &str) -> String {
+    KEY_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == key)
+        .map_or(key, |(_, name)| name)
+        .to_string()
+}
+
+/// Where a setting's value came from
+#[derive(Clone, Debug)]
+pub enum ValueOrigin {
+    File { path: PathBuf, line: usize },
+    Environment { variable: String },
 }
 
+impl std::fmt::Display for ValueOrigin {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ValueOrigin::File { path, line } => write!(f, "{}:{}", path.display(), line),
+            ValueOrigin::Environment { variable } => write!(f, "${}", variable),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ConfigValue {
+    pub value: String,
+    pub origin: ValueOrigin,
+}
+
+pub type Section = BTreeMap<String, ConfigValue>;
+type ConfigMap = BTreeMap<String, Section>;
+
 #[derive(Clone, Debug, Default)]
 pub struct Profile {
-    pub region: Region,
+    pub name: String,
+    pub region: Option<Region>,
     pub keypair: Option<String>,
     pub ssh_key: Option<String>,
     pub default_instance_type: Option<String>,
     pub security_groups: Option<Vec<String>>,
+    pub protect_tag: Option<String>,
+    pub max_retries: Option<u32>,
+    pub sts_endpoint: Option<String>,
+    pub endpoint_url: Option<String>,
+    pub ami_cache_ttl: Option<u64>,
+    pub ip_echo_url: Option<String>,
 }
 
 impl Profile {
-    fn add_value(&mut self, name: &str, value: &str) {
+    pub fn add_value(&mut self, name: &str, value: &str) -> std::result::Result<(), String> {
         match name {
             "region" => {
-                self.region = Region::from_str(value).expect("Error parsing AWS config file");
+                self.region = Some(
+                    Region::from_str(value).map_err(|_| format!("Invalid region '{}'", value))?,
+                );
             }
             "keypair" => self.keypair = Some(value.to_string()),
             "key" => self.ssh_key = Some(value.into()),
             "instance-type" => self.default_instance_type = Some(value.to_string()),
-            "security-groups" => {
-                self.security_groups = Some(
+            "security-groups" => self.security_groups = Some(split_list(value)),
+            "protect-tag" => self.protect_tag = Some(value.to_string()),
+            "max-retries" => {
+                self.max_retries = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("Invalid max-retries value '{}'", value))?,
+                );
+            }
+            "sts-endpoint" => self.sts_endpoint = Some(value.to_string()),
+            "endpoint-url" => self.endpoint_url = Some(value.to_string()),
+            "ami-cache-ttl" => {
+                self.ami_cache_ttl = Some(
                     value
-                        .split(", ")
-                        .map(|s| s.to_string())
-                        .collect::<Vec<String>>(),
-                )
+                        .parse()
+                        .map_err(|_| format!("Invalid ami-cache-ttl value '{}'", value))?,
+                );
             }
+            "ip-echo-url" => self.ip_echo_url = Some(value.to_string()),
             _ => (),
         }
+        Ok(())
     }
 }
 
 pub fn get_profile(profile_name: &str, config_file: &ConfigFileReader) -> Result<Profile> {
-    match config_file.get_profile(profile_name) {
-        Some(profile) => Ok(profile.clone()),
-        None => Err(AwsInstanceError::ProfileNotFoundError {
-            profile_name: profile_name.into(),
+    let mut profile = Profile {
+        name: profile_name.to_string(),
+        ..Default::default()
+    };
+    for (key, config_value) in config_file.resolve(profile_name)? {
+        profile
+            .add_value(&key, &config_value.value)
+            .map_err(|message| config_error(&config_value.origin, message))?;
+    }
+
+    Ok(profile)
+}
+
+/// Environment variables giving the region to use when neither the command line nor the
+/// profile does, in order of preference
+const REGION_VARIABLES: &[&str] = &["AWS_REGION", "AWS_DEFAULT_REGION"];
+
+pub fn fallback_region() -> Option<(String, String)> {
+    REGION_VARIABLES.iter().find_map(|variable| {
+        env::var(variable)
+            .ok()
+            .filter(|value| !value.is_empty())
+            .map(|value| (value, variable.to_string()))
+    })
+}
+
+/// Work out which region (and endpoint) to talk to, from the command line, the profile,
+/// the environment, or as a last resort, us-east-1
+pub fn resolve_region(
+    region: Option<&str>,
+    endpoint_url: Option<&str>,
+    profile: &Profile,
+    profile_name: &str,
+) -> Result<Region> {
+    let region = match (region, &profile.region, fallback_region()) {
+        (Some(region_name), _, _) => Region::from_str(region_name)?,
+        (None, Some(region), _) => region.clone(),
+        (None, None, Some((region_name, variable))) => {
+            Region::from_str(&region_name).map_err(|_| AwsInstanceError::ConfigError {
+                path: format!("${}", variable),
+                line: None,
+                message: format!("Invalid region '{}'", region_name),
+            })?
+        }
+        (None, None, None) => {
+            warn!(
+                "No region set for profile {}, using {}",
+                profile_name,
+                Region::default().name()
+            );
+            Region::default()
+        }
+    };
+
+    match endpoint_url.or(profile.endpoint_url.as_deref()) {
+        Some(endpoint) => Ok(Region::Custom {
+            name: region.name().into(),
+            endpoint: endpoint.into(),
         }),
+        None => Ok(region),
     }
 }
 
+pub fn config_error(origin: &ValueOrigin, message: String) -> AwsInstanceError {
+    match origin {
+        ValueOrigin::File { path, line } => AwsInstanceError::ConfigError {
+            path: path.display().to_string(),
+            line: Some(*line),
+            message,
+        },
+        ValueOrigin::Environment { variable } => AwsInstanceError::ConfigError {
+            path: format!("${}", variable),
+            line: None,
+            message,
+        },
+    }
+}
+
+/// The environment variable which overrides a profile setting, e.g. `AWS_INSTANCE_INSTANCE_TYPE`
+pub fn environment_variable(key: &str) -> String {
+    format!("AWS_INSTANCE_{}", key.to_uppercase().replace('-', "_"))
+}
+
 pub fn get_aws_config_file_path() -> PathBuf {
     match env::var_os("AWS_CONFIG_FILE") {
         Some(value) => PathBuf::from(value),
@@ -87,85 +230,185 @@ pub fn get_our_config_file_path(config_file: Option<String>) -> PathBuf {
     }
 }
 
+pub fn get_cache_dir() -> PathBuf {
+    let mut cache_path = dirs::home_dir().expect("Home directory not found");
+    cache_path.push(".aws-instance");
+    cache_path.push("cache");
+
+    cache_path
+}
+
+/// Where private keys created by `keypair create` are kept
+pub fn get_keys_dir() -> PathBuf {
+    let mut keys_path = dirs::home_dir().expect("Home directory not found");
+    keys_path.push(".aws-instance");
+    keys_path.push("keys");
+
+    keys_path
+}
+
 #[derive(Clone, Debug)]
 pub struct ConfigFileReader {
-    config_map: ConfigMap,
-    profile_name: Option<String>,
-    current_profile: Profile,
+    aws_config: ConfigMap,
+    our_config: ConfigMap,
+    templates: ConfigMap,
+    our_config_path: PathBuf,
 }
 
 impl ConfigFileReader {
-    pub fn new(config_file: Option<String>) -> Self {
-        let mut reader = ConfigFileReader {
-            config_map: ConfigMap::default(),
-            profile_name: None,
-            current_profile: Profile::default(),
-        };
-
-        reader.parse(&get_aws_config_file_path(), &AWS_CONFIG_SECTION_REGEX);
-        reader.parse(&get_our_config_file_path(config_file), &SECTION_REGEX);
-
-        reader
-    }
-
-    fn parse(&mut self, file_path: &PathBuf, section_regex: &Regex) {
-        if file_path.exists() {
-            let file = File::open(file_path.clone())
-                .unwrap_or_else(|_| panic!("Error opening config file {:?}", file_path));
-            for line_or_error in BufReader::new(file).lines() {
-                match line_or_error {
-                    Err(error) => panic!("Error reading config file: {}", error),
-                    Ok(line) => {
-                        self.parse_line(&line, section_regex);
-                    }
-                };
-            }
+    pub fn new(config_file: Option<String>) -> Result<Self> {
+        let our_config_path = get_our_config_file_path(config_file);
+        let (templates, our_config): (ConfigMap, ConfigMap) = Self::parse(&our_config_path, false)?
+            .into_iter()
+            .partition(|(name, _)| name.starts_with(TEMPLATE_PREFIX));
+        Ok(ConfigFileReader {
+            aws_config: Self::parse(&get_aws_config_file_path(), true)?,
+            our_config,
+            templates: templates
+                .into_iter()
+                .map(|(name, section)| (name[TEMPLATE_PREFIX.len()..].to_string(), section))
+                .collect(),
+            our_config_path,
+        })
+    }
+
+    pub fn our_config_path(&self) -> &Path {
+        &self.our_config_path
+    }
 
-            if self.profile_name.is_some() {
-                let name = self.clone().profile_name.unwrap();
-                let profile = self.current_profile.clone();
-                self.add_profile(name, profile);
+    fn parse(file_path: &Path, aws_config: bool) -> Result<ConfigMap> {
+        let mut config_map = ConfigMap::new();
+        if !file_path.exists() {
+            return Ok(config_map);
+        }
+
+        for section in parse_ini_file(file_path)? {
+            // The AWS config file names everything but the default profile "profile <name>"
+            let name = match section.name.strip_prefix("profile ") {
+                Some(name) if aws_config => name.trim(),
+                _ => section.name.as_str(),
+            };
+
+            // Repeated sections and keys are merged, with later values winning
+            let values = config_map.entry(name.to_string()).or_default();
+            for entry in section.entries {
+                values.insert(
+                    entry.key,
+                    ConfigValue {
+                        value: entry.value,
+                        origin: ValueOrigin::File {
+                            path: file_path.to_path_buf(),
+                            line: entry.line,
+                        },
+                    },
+                );
             }
         }
+
+        Ok(config_map)
     }
 
-    fn parse_line(&mut self, line: &str, section_regex: &Regex) {
-        if section_regex.is_match(line) {
-            let section_name = section_regex
-                .captures(line)
-                .unwrap()
-                .get(1)
-                .expect("No section name found")
-                .as_str();
-            self.set_section_name(section_name);
-        } else if VALUE_REGEX.is_match(line) {
-            let captures = VALUE_REGEX.captures(line).unwrap();
-            let key = captures.get(1).unwrap().as_str();
-            let value = captures.get(2).unwrap().as_str();
-            self.set_value(key, value);
+    /// All profile names, with whether each is in the AWS config file and our config file
+    pub fn list_profiles(&self) -> BTreeMap<String, (bool, bool)> {
+        let mut profiles = BTreeMap::new();
+        for name in self.aws_config.keys().chain(self.our_config.keys()) {
+            profiles.insert(
+                name.clone(),
+                (
+                    self.aws_config.contains_key(name),
+                    self.our_config.contains_key(name),
+                ),
+            );
         }
+        profiles
     }
 
-    fn set_section_name(&mut self, section_name: &str) {
-        if self.profile_name.is_some() {
-            let config = self.clone();
-            let profile_name = config.profile_name.unwrap();
-            let profile = config.current_profile;
-            self.add_profile(profile_name, profile);
-            self.current_profile = Profile::default();
-        }
-        self.profile_name = Some(section_name.to_string());
+    /// A section of the AWS config file, without any merging
+    pub fn aws_section(&self, name: &str) -> Option<&Section> {
+        self.aws_config.get(name)
+    }
+
+    /// A `[template.NAME]` section of our config file
+    pub fn template_section(&self, name: &str) -> Option<&Section> {
+        self.templates.get(name)
     }
 
-    fn set_value(&mut self, key: &str, value: &str) {
-        self.current_profile.add_value(key, value);
+    pub fn has_profile(&self, name: &str) -> bool {
+        self.aws_config.contains_key(name) || self.our_config.contains_key(name)
     }
 
-    fn add_profile(&mut self, name: String, profile: Profile) {
-        self.config_map.insert(name, profile);
+    /// The chain of profiles `name` inherits from in our config file, most distant ancestor first
+    fn inheritance_chain(&self, name: &str) -> Result<Vec<String>> {
+        let mut chain = vec![name.to_string()];
+        let mut current = name.to_string();
+
+        while let Some(parent) = self
+            .our_config
+            .get(&current)
+            .and_then(|section| INHERIT_KEYS.iter().find_map(|key| section.get(*key)))
+        {
+            if chain.contains(&parent.value) {
+                return Err(config_error(
+                    &parent.origin,
+                    format!("Inheritance loop through profile '{}'", parent.value),
+                ));
+            }
+            if !self.has_profile(&parent.value) {
+                return Err(config_error(
+                    &parent.origin,
+                    format!("Inherited profile '{}' not found", parent.value),
+                ));
+            }
+            chain.push(parent.value.clone());
+            current = parent.value.clone();
+        }
+
+        chain.reverse();
+        Ok(chain)
     }
 
-    pub fn get_profile(&self, name: &str) -> Option<&Profile> {
-        self.config_map.get(name)
+    /// Work out the effective settings for a profile. From lowest to highest precedence:
+    ///
+    /// 1. for each profile in the inheritance chain, starting with the most distant ancestor,
+    ///    its settings in the AWS config file followed by its settings in our config file
+    /// 2. `AWS_INSTANCE_*` environment variables
+    ///
+    /// A profile which isn't in either file just gets the environment variables, since
+    /// AWS may be configured entirely through the environment. Callers check that a profile
+    /// named on the command line exists.
+    pub fn resolve(&self, name: &str) -> Result<Section> {
+        let mut values = Section::new();
+        for profile_name in self.inheritance_chain(name)? {
+            if let Some(section) = self.aws_config.get(&profile_name) {
+                values.extend(
+                    section
+                        .iter()
+                        .map(|(key, value)| (canonical_key(key), value.clone())),
+                );
+            }
+            if let Some(section) = self.our_config.get(&profile_name) {
+                values.extend(
+                    section
+                        .iter()
+                        .filter(|(key, _)| !INHERIT_KEYS.contains(&key.as_str()))
+                        .map(|(key, value)| (canonical_key(key), value.clone())),
+                );
+            }
+        }
+
+        for key in PROFILE_KEYS {
+            let variable = environment_variable(key);
+            if let Ok(value) = env::var(&variable) {
+                values.insert(
+                    key.to_string(),
+                    ConfigValue {
+                        value,
+                        origin: ValueOrigin::Environment { variable },
+                    },
+                );
+            }
+        }
+
+        Ok(values)
     }
 }
@@ -0,0 +1,239 @@
+use futures::FutureExt;
+use log::warn;
+use rusoto_core::request::HttpDispatchError;
+use rusoto_core::request::{
+    BufferedHttpResponse, DispatchSignedRequest, DispatchSignedRequestFuture, HttpResponse,
+};
+use rusoto_core::signature::{SignedRequest, SignedRequestPayload};
+use rusoto_core::HttpClient;
+use rusoto_credential::{Anonymous, ProvideAwsCredentials};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const DEFAULT_MAX_RETRIES: u32 = 4;
+
+const BASE_DELAY_MS: u64 = 250;
+const MAX_DELAY_MS: u64 = 20_000;
+
+// Error codes AWS uses to tell us to slow down
+pub const THROTTLING_CODES: &[&str] = &[
+    "RequestLimitExceeded",
+    "Throttling",
+    "ThrottlingException",
+    "RequestThrottled",
+    "TooManyRequestsException",
+];
+
+// Changes which are safe to repeat, besides anything read-only
+const IDEMPOTENT_ACTIONS: &[&str] = &[
+    "CreateTags",
+    "DeleteTags",
+    "RebootInstances",
+    "StartInstances",
+    "StopInstances",
+    "TerminateInstances",
+];
+
+#[derive(Debug, PartialEq)]
+enum ErrorClass {
+    Throttling,
+    ServerError,
+    Dispatch,
+    Permanent,
+}
+
+impl std::fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                ErrorClass::Throttling => "request throttled",
+                ErrorClass::ServerError => "server error",
+                ErrorClass::Dispatch => "HTTP dispatch error",
+                ErrorClass::Permanent => "permanent error",
+            }
+        )
+    }
+}
+
+fn classify(response: &BufferedHttpResponse) -> ErrorClass {
+    let body = response.body_as_str();
+    if response.status.as_u16() == 429
+        || THROTTLING_CODES
+            .iter()
+            .any(|code| body.contains(&format!("<Code>{}</Code>", code)))
+    {
+        ErrorClass::Throttling
+    } else if response.status.is_server_error() {
+        ErrorClass::ServerError
+    } else {
+        ErrorClass::Permanent
+    }
+}
+
+fn form_value<'a>(payload: &'a str, name: &str) -> Option<&'a str> {
+    payload.split('&').find_map(|pair| {
+        pair.strip_prefix(name)
+            .and_then(|value| value.strip_prefix('='))
+    })
+}
+
+/// A server error or dropped connection might come after the change was made, so only
+/// retry those for requests which can't do the work twice. Throttled requests were
+/// turned away before doing anything, so they're always safe to retry.
+fn is_safe_to_repeat(request: &SignedRequest) -> bool {
+    let payload = match request.payload {
+        Some(SignedRequestPayload::Buffer(ref buffer)) => String::from_utf8_lossy(buffer),
+        _ => return false,
+    };
+    if form_value(&payload, "ClientToken").is_some() {
+        return true;
+    }
+    match form_value(&payload, "Action") {
+        Some(action) => {
+            ["Describe", "Get"]
+                .iter()
+                .any(|prefix| action.starts_with(prefix))
+                || IDEMPOTENT_ACTIONS.contains(&action)
+        }
+        None => false,
+    }
+}
+
+/// Exponential backoff with "full jitter", so parallel callers don't retry in lockstep
+fn backoff(attempt: u32) -> Duration {
+    let ceiling = BASE_DELAY_MS
+        .saturating_mul(1 << attempt.min(16))
+        .min(MAX_DELAY_MS);
+    Duration::from_millis(fastrand::u64(ceiling / 2..=ceiling))
+}
+
+// SignedRequest isn't Clone, but everything the EC2 client sends is buffered, so we can
+// copy it field by field. Streaming payloads can't be replayed, so those aren't retried.
+// The copy keeps the old signature, which is replaced when it's signed again.
+fn copy_request(request: &SignedRequest) -> Option<SignedRequest> {
+    let payload = match request.payload {
+        None => None,
+        Some(SignedRequestPayload::Buffer(ref buffer)) => {
+            Some(SignedRequestPayload::Buffer(buffer.clone()))
+        }
+        Some(SignedRequestPayload::Stream(_)) => return None,
+    };
+
+    Some(SignedRequest {
+        method: request.method.clone(),
+        service: request.service.clone(),
+        region: request.region.clone(),
+        path: request.path.clone(),
+        headers: request.headers.clone(),
+        params: request.params.clone(),
+        scheme: request.scheme.clone(),
+        hostname: request.hostname.clone(),
+        payload,
+        canonical_query_string: request.canonical_query_string.clone(),
+        canonical_uri: request.canonical_uri.clone(),
+    })
+}
+
+/// Wraps the rusoto HTTP client, retrying throttled requests, and server errors and
+/// dispatch failures for requests which are safe to repeat, so every call made through
+/// the EC2 client gets the same treatment. Each retry is signed again, so a long backoff
+/// can't leave it with an expired signature or session token.
+pub struct RetryingHttpClient<P> {
+    inner: Arc<HttpClient>,
+    credentials_provider: Arc<P>,
+    max_retries: u32,
+}
+
+impl<P> RetryingHttpClient<P> {
+    pub fn new(inner: HttpClient, credentials_provider: Arc<P>, max_retries: u32) -> Self {
+        RetryingHttpClient {
+            inner: Arc::new(inner),
+            credentials_provider,
+            max_retries,
+        }
+    }
+}
+
+async fn sign_again<P: ProvideAwsCredentials>(
+    request: &mut SignedRequest,
+    credentials_provider: &P,
+) -> Result<(), HttpDispatchError> {
+    let credentials = credentials_provider
+        .credentials()
+        .await
+        .map_err(|error| HttpDispatchError::new(error.message))?;
+    if !credentials.is_anonymous() {
+        // Otherwise the old signature would be signed as one of the headers
+        request.remove_header("authorization");
+        request.sign(&credentials);
+    }
+    Ok(())
+}
+
+impl<P: ProvideAwsCredentials + Send + Sync + 'static> DispatchSignedRequest
+    for RetryingHttpClient<P>
+{
+    fn dispatch(
+        &self,
+        request: SignedRequest,
+        timeout: Option<Duration>,
+    ) -> DispatchSignedRequestFuture {
+        let inner = self.inner.clone();
+        let credentials_provider = self.credentials_provider.clone();
+        let max_retries = self.max_retries;
+
+        async move {
+            let safe_to_repeat = is_safe_to_repeat(&request);
+            let mut attempt = 0;
+            loop {
+                let mut attempt_request = match copy_request(&request) {
+                    Some(attempt_request) => attempt_request,
+                    None => return inner.dispatch(request, timeout).await,
+                };
+                if attempt > 0 {
+                    sign_again(&mut attempt_request, credentials_provider.as_ref()).await?;
+                }
+
+                let error_class = match inner.dispatch(attempt_request, timeout).await {
+                    Ok(response) if response.status.is_success() => return Ok(response),
+                    Ok(mut response) => {
+                        let buffered = response.buffer().await?;
+                        let error_class = classify(&buffered);
+                        if error_class == ErrorClass::Permanent
+                            || (error_class == ErrorClass::ServerError && !safe_to_repeat)
+                            || attempt >= max_retries
+                        {
+                            return Ok(HttpResponse {
+                                status: buffered.status,
+                                body: buffered.body.to_vec().into(),
+                                headers: buffered.headers,
+                            });
+                        }
+                        error_class
+                    }
+                    Err(error) => {
+                        if !safe_to_repeat || attempt >= max_retries {
+                            return Err(error);
+                        }
+                        ErrorClass::Dispatch
+                    }
+                };
+
+                attempt += 1;
+                let delay = backoff(attempt);
+                warn!(
+                    "{} calling {}, retrying in {}ms (retry {} of {})",
+                    error_class,
+                    request.service,
+                    delay.as_millis(),
+                    attempt,
+                    max_retries
+                );
+                tokio::time::sleep(delay).await;
+            }
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,80 @@
+use std::str::FromStr;
+
+use crate::cmdline::OsNames;
+use crate::commands::create::{parse_tag, LaunchTemplateRef, Volume};
+use crate::ini::split_list;
+use crate::profile::{config_error, ConfigFileReader};
+use crate::{AwsInstanceError, Result};
+
+/// Settings for `create` kept in a `[template.NAME]` section of our config file
+#[derive(Clone, Debug, Default)]
+pub struct Template {
+    pub ami_id: Option<String>,
+    pub launch_template: Option<LaunchTemplateRef>,
+    pub os_name: Option<OsNames>,
+    pub architecture: Option<String>,
+    pub instance_type: Option<String>,
+    pub volumes: Vec<Volume>,
+    pub security_groups: Option<Vec<String>>,
+    pub subnet_id: Option<String>,
+    pub tags: Vec<(String, String)>,
+    pub user_data: Option<String>,
+    pub iam_profile: Option<String>,
+    pub ebs_optimized: Option<bool>,
+}
+
+impl Template {
+    pub fn add_value(&mut self, name: &str, value: &str) -> std::result::Result<(), String> {
+        match name {
+            "ami-id" => self.ami_id = Some(value.to_string()),
+            "launch-template" => self.launch_template = Some(LaunchTemplateRef::from_str(value)?),
+            "os-name" => {
+                self.os_name = Some(OsNames::from_str(value).map_err(|error| error.to_string())?)
+            }
+            "architecture" => self.architecture = Some(value.to_string()),
+            "instance-type" => self.instance_type = Some(value.to_string()),
+            "volumes" => {
+                self.volumes = split_list(value)
+                    .iter()
+                    .map(|volume| Volume::from_str(volume))
+                    .collect::<std::result::Result<_, _>>()?;
+            }
+            "security-groups" => self.security_groups = Some(split_list(value)),
+            "subnet" => self.subnet_id = Some(value.to_string()),
+            "tags" => {
+                self.tags = split_list(value)
+                    .iter()
+                    .map(|tag| parse_tag(tag))
+                    .collect::<std::result::Result<_, _>>()?;
+            }
+            "user-data" => self.user_data = Some(value.to_string()),
+            "iam-profile" => self.iam_profile = Some(value.to_string()),
+            "ebs-optimized" => {
+                self.ebs_optimized = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("Invalid ebs-optimized value '{}'", value))?,
+                );
+            }
+            _ => return Err(format!("Unknown template setting '{}'", name)),
+        }
+        Ok(())
+    }
+}
+
+pub fn get_template(template_name: &str, config_file: &ConfigFileReader) -> Result<Template> {
+    let section = config_file.template_section(template_name).ok_or_else(|| {
+        AwsInstanceError::TemplateNotFoundError {
+            template_name: template_name.into(),
+        }
+    })?;
+
+    let mut template = Template::default();
+    for (key, config_value) in section {
+        template
+            .add_value(key, &config_value.value)
+            .map_err(|message| config_error(&config_value.origin, message))?;
+    }
+
+    Ok(template)
+}
@@ -1,6 +1,12 @@
-use crate::{cmdline::OsNames, Result};
+use crate::{cmdline::OsNames, AwsInstanceError, Result};
+use hyper_tls::HttpsConnector;
 use rusoto_ec2::{Ec2, Ec2Client};
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::net::IpAddr;
+use std::path::Path;
 use std::str::FromStr;
+use std::time::Duration;
 
 pub fn get_name(instance: &rusoto_ec2::Instance) -> String {
     match instance.tags {
@@ -20,6 +26,14 @@ pub fn get_name(instance: &rusoto_ec2::Instance) -> String {
     String::new()
 }
 
+pub fn has_tag(instance: &rusoto_ec2::Instance, key: &str) -> bool {
+    instance
+        .tags
+        .as_ref()
+        .map(|tags| tags.iter().any(|tag| tag.key.as_deref() == Some(key)))
+        .unwrap_or(false)
+}
+
 pub fn get_state(instance: &rusoto_ec2::Instance) -> String {
     match instance.state {
         Some(ref state) => match state.name {
@@ -60,33 +74,39 @@ pub async fn get_os_for_instance(instance: &rusoto_ec2::Instance) -> Option<OsNa
     }) {
         None => None,
         Some(None) => None,
-        Some(Some(value)) => match OsNames::from_str(&value) {
-            Ok(value) => Some(value),
-            Err(_) => None,
-        },
+        Some(Some(value)) => OsNames::from_str(&value).ok(),
     }
 }
 
 pub async fn get_all_instances(ec2_client: &Ec2Client) -> Result<Vec<rusoto_ec2::Instance>> {
-    let request = rusoto_ec2::DescribeInstancesRequest {
-        dry_run: Some(false),
-        filters: None,
-        instance_ids: None,
-        max_results: None,
-        next_token: None,
-    };
-
     let mut instances = Vec::new();
+    let mut next_token = None;
 
-    let result = ec2_client.describe_instances(request).await?;
-    if result.reservations.is_some() {
-        for reservation in result.reservations.unwrap() {
-            if reservation.instances.is_some() {
-                for instance in reservation.instances.unwrap() {
-                    instances.push(instance);
+    // Results come a page at a time, so keep asking until there's no token for another
+    loop {
+        let request = rusoto_ec2::DescribeInstancesRequest {
+            dry_run: Some(false),
+            filters: None,
+            instance_ids: None,
+            max_results: None,
+            next_token,
+        };
+
+        let result = ec2_client.describe_instances(request).await?;
+        if let Some(reservations) = result.reservations {
+            for reservation in reservations {
+                if let Some(reservation_instances) = reservation.instances {
+                    for instance in reservation_instances {
+                        instances.push(instance);
+                    }
                 }
             }
         }
+
+        next_token = result.next_token.filter(|token| !token.is_empty());
+        if next_token.is_none() {
+            break;
+        }
     }
 
     Ok(instances)
@@ -102,3 +122,113 @@ pub fn print_state_changes(state_changes: Vec<rusoto_ec2::InstanceStateChange>)
         );
     }
 }
+
+/// Ask the user for a line of input. The prompt goes to stderr so it doesn't get mixed
+/// up with output that might be piped somewhere.
+pub fn prompt(prompt: &str) -> Result<String> {
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+
+    Ok(answer.trim().to_string())
+}
+
+/// Ask the user to retype `expected` to confirm a destructive action
+pub fn confirm(prompt_text: &str, expected: &str) -> Result<bool> {
+    Ok(prompt(prompt_text)? == expected)
+}
+
+/// EC2 reports a successful dry run as a `DryRunOperation` error, so treat that as success
+pub fn dry_run_ok(error: AwsInstanceError, action: &str) -> Result<()> {
+    match error {
+        AwsInstanceError::DryRunOperation { .. } => {
+            println!("Dry run: would {}", action);
+            Ok(())
+        }
+        error => Err(error),
+    }
+}
+
+/// Write a file only we can read, creating its directory (also private) if need be. The
+/// permissions are set even if the file was already there, since `mode` only applies to new
+/// files. Unless `replace` is set, an existing file is an error.
+pub fn write_private_file(path: &Path, contents: &[u8], replace: bool) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true);
+    if replace {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+        if let Some(parent) = path.parent() {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(parent)?;
+        }
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(contents)
+}
+
+/// A random token for requests which accept one, so EC2 can spot a retried request
+/// and return the original result instead of doing the work twice
+pub fn client_token() -> String {
+    format!("{:016x}{:016x}", fastrand::u64(..), fastrand::u64(..))
+}
+
+/// Service which replies with the caller's public IP address
+pub const DEFAULT_IP_ECHO_URL: &str = "https://checkip.amazonaws.com/";
+
+const PUBLIC_IP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Find our public IP address, as seen from the internet
+pub async fn public_ip(echo_url: &str) -> Result<IpAddr> {
+    let error = |message: String| AwsInstanceError::PublicIpError {
+        url: echo_url.to_string(),
+        message,
+    };
+
+    let uri: hyper::Uri = echo_url
+        .parse()
+        .map_err(|uri_error| AwsInstanceError::Validation {
+            message: format!("Invalid IP echo URL '{}': {}", echo_url, uri_error),
+        })?;
+    let client = hyper::Client::builder().build::<_, hyper::Body>(HttpsConnector::new());
+
+    let fetch = async {
+        let response = client.get(uri).await?;
+        let status = response.status();
+        hyper::body::to_bytes(response.into_body())
+            .await
+            .map(|body| (status, body))
+    };
+    let (status, body) = tokio::time::timeout(PUBLIC_IP_TIMEOUT, fetch)
+        .await
+        .map_err(|_| error(format!("no answer after {}s", PUBLIC_IP_TIMEOUT.as_secs())))?
+        .map_err(|http_error| error(http_error.to_string()))?;
+    if !status.is_success() {
+        return Err(error(format!("HTTP status {}", status)));
+    }
+
+    let body = String::from_utf8_lossy(&body);
+    let body = body.trim();
+    body.parse()
+        .map_err(|_| error(format!("Unexpected response '{}'", body)))
+}